@@ -2,7 +2,9 @@
 extern crate log;
 use sled::IVec;
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::fmt::Display;
+use std::time::Duration;
 /// Entry trait that will be used to be able to fetch and cache data as the Key
 pub trait Entry {
     type Key: EntryKey + Send + Sync;
@@ -10,6 +12,56 @@ pub trait Entry {
 
     fn key(&self) -> Self::Key;
     fn value(&self) -> Self::Value;
+    /// Return the mirror urls to fall back to, in order, if fetching `value().url()` fails
+    fn mirrors(&self) -> Vec<String> {
+        Vec::new()
+    }
+    /// Return the expected SHA-256 (hex) of the response, a mismatch fails over to the next mirror
+    fn checksum(&self) -> Option<String> {
+        None
+    }
+    /// Return how the request for this entry should be built (method, headers, query, body, timeout)
+    fn request(&self) -> RequestSpec {
+        RequestSpec::default()
+    }
+    /// Return the version of the entry, if it has one
+    fn version(&self) -> Option<String> {
+        None
+    }
+    /// Return the tags (or groups) of the entry, used to select entries to fetch or write
+    fn tags(&self) -> Vec<String> {
+        Vec::new()
+    }
+    /// Return the keys of the entries this entry depends on
+    fn depends(&self) -> Vec<Self::Key> {
+        Vec::new()
+    }
+    /// Return how long the cached value stays fresh, after which it is fetched again even if
+    /// `is_same` holds (overrides the fetcher's TTL)
+    fn ttl(&self) -> Option<Duration> {
+        None
+    }
+    /// Return whether to compress the cached value at rest (overrides the fetcher's compression)
+    fn compress(&self) -> Option<bool> {
+        None
+    }
+}
+
+/// Customizes the request sent for an entry
+///
+/// - `method`: HTTP method (defaults to `GET`)
+/// - `headers`: Extra headers, only sent to the origin of the entry's own url (not to mirrors,
+///   rewritten hosts or redirects to another origin)
+/// - `query`: Query parameters appended to the url
+/// - `body`: Request body
+/// - `timeout`: Timeout of the whole request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestSpec {
+    pub method: Option<String>,
+    pub headers: BTreeMap<String, String>,
+    pub query: BTreeMap<String, String>,
+    pub body: Option<Vec<u8>>,
+    pub timeout: Option<Duration>,
 }
 
 pub trait EntryKey: Display {
@@ -41,10 +93,17 @@ impl EntryKey for String {
 pub trait EntryValue {
     /// Convert the value to bytes
     fn bytes(&self) -> Vec<u8>;
-    /// Convert the value from IVec
-    fn from_ivec(value: IVec) -> Self
+    /// Convert the value from IVec, failing if it can't be read
+    fn try_from_ivec(value: IVec) -> anyhow::Result<Self>
     where
         Self: Sized;
+    /// Convert the value from IVec, panicking if it can't be read
+    fn from_ivec(value: IVec) -> Self
+    where
+        Self: Sized,
+    {
+        Self::try_from_ivec(value).unwrap_or_else(|e| panic!("Failed to read value: {:#}", e))
+    }
     fn from_bytes(value: &[u8]) -> Self
     where
         Self: Sized,
@@ -61,4 +120,52 @@ pub trait EntryValue {
     fn is_same(&self, other: &Self) -> bool
     where
         Self: Sized;
+    /// Return the revision the cached response was fetched at (e.g. the commit of a git ref)
+    fn revision(&self) -> Option<String> {
+        None
+    }
+    /// Set the revision the response is fetched at
+    fn set_revision(&mut self, _revision: &str) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A value read as UTF-8, anything else can't be read
+    struct Text(String);
+
+    impl EntryValue for Text {
+        fn bytes(&self) -> Vec<u8> {
+            self.0.as_bytes().to_vec()
+        }
+        fn try_from_ivec(value: IVec) -> anyhow::Result<Self> {
+            Ok(Self(String::from_utf8(value.to_vec())?))
+        }
+        fn url(&self) -> String {
+            String::new()
+        }
+        fn response(&self) -> Cow<'_, [u8]> {
+            Cow::Borrowed(self.0.as_bytes())
+        }
+        fn set_response(&mut self, response: &[u8]) {
+            self.0 = String::from_utf8_lossy(response).to_string();
+        }
+        fn is_same(&self, other: &Self) -> bool {
+            self.0 == other.0
+        }
+    }
+
+    #[test]
+    fn from_ivec_reads_with_try_from_ivec() {
+        assert_eq!(Text::from_ivec(IVec::from("text")).0, "text");
+        assert_eq!(Text::from_bytes(b"text").0, "text");
+        assert!(Text::try_from_ivec(IVec::from(&[0xff, 0xfe][..])).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "Failed to read value")]
+    fn from_ivec_panics_on_unreadable_values() {
+        Text::from_ivec(IVec::from(&[0xff, 0xfe][..]));
+    }
 }
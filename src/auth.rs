@@ -0,0 +1,339 @@
+use anyhow::Result;
+use reqwest::RequestBuilder;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Debug, Formatter};
+use std::path::Path;
+
+/// Credential sent to a host
+///
+/// - `Basic`: HTTP basic authentication
+/// - `Bearer`: `Authorization: Bearer <token>`
+/// - `Header`: A custom header such as `X-JFrog-Art-Api`
+///
+/// In a config file the scheme is given with the `scheme` field:
+///
+/// ```toml
+/// [credentials."artifactory.internal"]
+/// scheme = "bearer"
+/// token = "..."
+/// ```
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "scheme", rename_all = "lowercase")]
+pub enum Credential {
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+    Bearer {
+        token: String,
+    },
+    Header {
+        name: String,
+        value: String,
+    },
+}
+
+impl Debug for Credential {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // Never print the secrets themselves
+        match self {
+            Credential::Basic { username, .. } => write!(f, "Basic({}:***)", username),
+            Credential::Bearer { .. } => write!(f, "Bearer(***)"),
+            Credential::Header { name, .. } => write!(f, "Header({}: ***)", name),
+        }
+    }
+}
+
+impl Credential {
+    /// Attaches the credential to a request
+    pub fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            Credential::Basic { username, password } => {
+                request.basic_auth(username, password.as_ref())
+            }
+            Credential::Bearer { token } => request.bearer_auth(token),
+            Credential::Header { name, value } => request.header(name, value),
+        }
+    }
+}
+
+/// Provides the credential to send to a host
+///
+/// Providers are consulted in the order they were added to the `Fetcher`, and the first
+/// credential returned is used. Credentials are only sent to the origin of the requested url
+/// and are dropped once a redirect leaves it.
+pub trait CredentialProvider: Debug + Send + Sync {
+    /// Returns the credential for the host, if any
+    fn credential(&self, host: &str) -> Option<Credential>;
+}
+
+/// Credentials from a `.netrc` file (`machine`, `login` and `password` entries, sent as basic auth)
+#[derive(Clone, Default)]
+pub struct NetrcProvider {
+    machines: HashMap<String, Credential>,
+    default: Option<Credential>,
+}
+
+impl Debug for NetrcProvider {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NetrcProvider")
+            .field("machines", &self.machines.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl NetrcProvider {
+    /// Parses a netrc file
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    /// Parses the file at `NETRC` if set, otherwise `~/.netrc`
+    pub fn load() -> Result<Self> {
+        match std::env::var_os("NETRC") {
+            Some(path) => Self::new(path),
+            None => Self::new(crate::home_plus(".netrc")),
+        }
+    }
+
+    /// Parses the contents of a netrc file
+    pub fn parse(contents: &str) -> Self {
+        // Macro definitions run until the next blank line and hold no credentials, and a
+        // comment starts with a token beginning with `#` (a password may contain one)
+        let mut tokens = Vec::new();
+        let mut in_macdef = false;
+        for line in contents.lines() {
+            if in_macdef {
+                in_macdef = !line.trim().is_empty();
+                continue;
+            }
+            for token in line.split_whitespace() {
+                if token.starts_with('#') {
+                    break;
+                }
+                if token == "macdef" {
+                    in_macdef = true;
+                    break;
+                }
+                tokens.push(token);
+            }
+        }
+
+        let mut provider = Self::default();
+        let mut machine: Option<Option<String>> = None;
+        let (mut login, mut password) = (None, None);
+        let mut finish =
+            |machine: Option<Option<String>>, login: Option<String>, password: Option<String>| {
+                if let (Some(machine), Some(username)) = (machine, login) {
+                    let credential = Credential::Basic { username, password };
+                    match machine {
+                        Some(host) => {
+                            provider.machines.entry(host).or_insert(credential);
+                        }
+                        None => provider.default = Some(credential),
+                    }
+                }
+            };
+
+        let mut iter = tokens.into_iter();
+        while let Some(token) = iter.next() {
+            match token {
+                "machine" | "default" => {
+                    finish(machine.take(), login.take(), password.take());
+                    machine = Some(match token {
+                        "machine" => iter.next().map(String::from),
+                        _ => None,
+                    });
+                }
+                "login" => login = iter.next().map(String::from),
+                "password" => password = iter.next().map(String::from),
+                "account" => {
+                    iter.next();
+                }
+                _ => {}
+            }
+        }
+        finish(machine, login, password);
+        provider
+    }
+}
+
+impl CredentialProvider for NetrcProvider {
+    fn credential(&self, host: &str) -> Option<Credential> {
+        self.machines.get(host).or(self.default.as_ref()).cloned()
+    }
+}
+
+/// Credentials from environment variables named after the host
+///
+/// For `artifactory.internal` and the default `QUICKFETCH` prefix, the variables are:
+/// - `QUICKFETCH_TOKEN_ARTIFACTORY_INTERNAL`: a bearer token
+/// - `QUICKFETCH_USERNAME_ARTIFACTORY_INTERNAL` and `QUICKFETCH_PASSWORD_ARTIFACTORY_INTERNAL`: basic auth
+/// - `QUICKFETCH_HEADER_ARTIFACTORY_INTERNAL`: a custom header as `Name: value`
+#[derive(Debug, Clone)]
+pub struct EnvProvider {
+    prefix: String,
+}
+
+impl Default for EnvProvider {
+    fn default() -> Self {
+        Self::new("QUICKFETCH")
+    }
+}
+
+impl EnvProvider {
+    pub fn new<S: Into<String>>(prefix: S) -> Self {
+        Self {
+            prefix: prefix.into(),
+        }
+    }
+
+    fn var(&self, kind: &str, host: &str) -> Option<String> {
+        let host = host
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() {
+                true => c.to_ascii_uppercase(),
+                false => '_',
+            })
+            .collect::<String>();
+        std::env::var(format!("{}_{}_{}", self.prefix, kind, host)).ok()
+    }
+}
+
+impl CredentialProvider for EnvProvider {
+    fn credential(&self, host: &str) -> Option<Credential> {
+        if let Some(token) = self.var("TOKEN", host) {
+            return Some(Credential::Bearer { token });
+        }
+        if let Some(username) = self.var("USERNAME", host) {
+            let password = self.var("PASSWORD", host);
+            return Some(Credential::Basic { username, password });
+        }
+        let header = self.var("HEADER", host)?;
+        let (name, value) = header.split_once(':')?;
+        Some(Credential::Header {
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+        })
+    }
+}
+
+/// Fixed credentials by host, this is the `[credentials]` table of a config file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct StaticProvider {
+    credentials: BTreeMap<String, Credential>,
+}
+
+impl StaticProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the credential for a host
+    pub fn insert<S: Into<String>>(&mut self, host: S, credential: Credential) {
+        self.credentials.insert(host.into(), credential);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.credentials.is_empty()
+    }
+}
+
+impl CredentialProvider for StaticProvider {
+    fn credential(&self, host: &str) -> Option<Credential> {
+        self.credentials.get(host).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::FetcherBuilder;
+    use crate::package::SimplePackage;
+    use crate::testing::{response, serve};
+    use crate::FetchMethod;
+
+    fn basic(username: &str, password: &str) -> Option<Credential> {
+        Some(Credential::Basic {
+            username: username.to_string(),
+            password: Some(password.to_string()),
+        })
+    }
+
+    #[test]
+    fn parses_netrc() {
+        let netrc = NetrcProvider::parse(
+            "# Credentials for the mirrors\n\
+             machine a.example.com login alice password abc#123 # comment\n\
+             machine b.example.com\n  login bob\n  password hunter2\n\
+             macdef init\n  machine c.example.com login eve password evil\n\n\
+             default login anonymous password guest\n",
+        );
+        assert_eq!(netrc.credential("a.example.com"), basic("alice", "abc#123"));
+        assert_eq!(netrc.credential("b.example.com"), basic("bob", "hunter2"));
+        // The macro body isn't read as entries, so the host falls back to the default
+        assert_eq!(
+            netrc.credential("c.example.com"),
+            basic("anonymous", "guest")
+        );
+    }
+
+    #[test]
+    fn reads_credentials_from_the_environment() {
+        let env = EnvProvider::new("QUICKFETCH_AUTH_TEST");
+        std::env::set_var("QUICKFETCH_AUTH_TEST_TOKEN_TOKEN_EXAMPLE_COM", "secret");
+        std::env::set_var("QUICKFETCH_AUTH_TEST_USERNAME_BASIC_EXAMPLE_COM", "alice");
+        std::env::set_var("QUICKFETCH_AUTH_TEST_PASSWORD_BASIC_EXAMPLE_COM", "abc");
+        std::env::set_var(
+            "QUICKFETCH_AUTH_TEST_HEADER_HEADER_EXAMPLE_COM",
+            "X-Api-Key: key",
+        );
+
+        assert_eq!(
+            env.credential("token.example.com"),
+            Some(Credential::Bearer {
+                token: "secret".to_string()
+            })
+        );
+        assert_eq!(env.credential("basic.example.com"), basic("alice", "abc"));
+        assert_eq!(
+            env.credential("header.example.com"),
+            Some(Credential::Header {
+                name: "X-Api-Key".to_string(),
+                value: "key".to_string()
+            })
+        );
+        assert_eq!(env.credential("none.example.com"), None);
+    }
+
+    #[tokio::test]
+    async fn drops_credentials_on_cross_origin_redirects() {
+        let other = serve(|_| response(200, &[], "payload")).await;
+        let location = format!("{}/b.bin", other.url);
+        let origin = serve(move |_| response(302, &[("Location", &location)], "")).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let package = SimplePackage::new("a", "1.0", &format!("{}/a.bin", origin.url));
+        let mut fetcher = FetcherBuilder::in_memory(dir.path().join("db"))
+            .build_with_entries(vec![package])
+            .unwrap();
+        // Both servers are on 127.0.0.1, only their ports differ
+        let mut credentials = StaticProvider::new();
+        credentials.insert(
+            "127.0.0.1",
+            Credential::Bearer {
+                token: "secret".to_string(),
+            },
+        );
+        fetcher.add_credential_provider(credentials);
+        fetcher.fetch(FetchMethod::Async).await.unwrap();
+
+        let authorized = |head: &String| head.to_lowercase().contains("authorization: bearer");
+        assert_eq!(origin.requests().len(), 1);
+        assert!(origin.requests().iter().all(authorized));
+        assert!(!other.requests().iter().any(authorized));
+        assert_eq!(other.requests().len(), 1);
+    }
+}
@@ -0,0 +1,460 @@
+use crate::cache::Quota;
+use crate::compress::Compression;
+use crate::crypt::{EncryptionMethod, KeyProvider};
+use crate::lock::LOCKFILE;
+use crate::package::{Config, Mode};
+use crate::{history, meta, stored, FetchMode, Fetcher, NotifyMethod, ResponseMethod};
+use anyhow::{bail, Result};
+use indicatif::MultiProgress;
+use quickfetch_traits::Entry;
+use reqwest::redirect::Policy;
+use reqwest::{Certificate, Client, Identity, Proxy};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// Builder for a `Fetcher` and the HTTP client it uses
+///
+/// Combinations that `Fetcher` can't work with (such as a progress bar with
+/// `ResponseMethod::Bytes`) are rejected by `build` instead of panicking later.
+///
+/// ```no_run
+/// # use quickfetch::prelude::*;
+/// # use quickfetch::{NotifyMethod, ResponseMethod};
+/// # async fn run() -> anyhow::Result<()> {
+/// let fetcher: Fetcher<SimplePackage> = FetcherBuilder::new("pkgs.toml", Mode::Toml, "db")
+///     .user_agent("ourpm/1.0")
+///     .connect_timeout(std::time::Duration::from_secs(10))
+///     .concurrency(8)
+///     .response_method(ResponseMethod::Chunk)
+///     .notify_method(NotifyMethod::ProgressBar)
+///     .build()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct FetcherBuilder {
+    config_path: Option<PathBuf>,
+    config_type: Option<Mode>,
+    db_path: PathBuf,
+    user_agent: Option<String>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    proxies: Vec<String>,
+    root_certificates: Vec<PathBuf>,
+    client_certificate: Option<(PathBuf, PathBuf)>,
+    http2_prior_knowledge: bool,
+    max_redirects: usize,
+    gzip: bool,
+    deflate: bool,
+    brotli: bool,
+    zstd: bool,
+    concurrency: Option<usize>,
+    response_method: ResponseMethod,
+    notify_method: NotifyMethod,
+    lock_path: Option<PathBuf>,
+    lockfile: Option<bool>,
+    fetch_mode: FetchMode,
+    auto_prune: bool,
+    quota: Quota,
+    ttl: Option<Duration>,
+    keep_versions: usize,
+    transactional: bool,
+    compression: Compression,
+    encryption_method: EncryptionMethod,
+    key_provider: Option<Arc<dyn KeyProvider>>,
+}
+
+impl FetcherBuilder {
+    /// Creates a builder for a `Fetcher` reading `config_path` and caching to `db_path`
+    pub fn new<P: AsRef<Path>>(config_path: P, config_type: Mode, db_path: P) -> Self {
+        Self {
+            config_path: Some(config_path.as_ref().to_path_buf()),
+            config_type: Some(config_type),
+            ..Self::in_memory(db_path)
+        }
+    }
+
+    /// Creates a builder for a `Fetcher` reading `config_path` and caching to `db_path`,
+    /// detecting the format of the config from its extension or contents
+    pub fn open<P: AsRef<Path>>(config_path: P, db_path: P) -> Self {
+        Self {
+            config_path: Some(config_path.as_ref().to_path_buf()),
+            ..Self::in_memory(db_path)
+        }
+    }
+
+    /// Creates a builder for a `Fetcher` without a config file caching to `db_path`
+    ///
+    /// The entries are given with `build_with_config` or `build_with_entries`
+    pub fn in_memory<P: AsRef<Path>>(db_path: P) -> Self {
+        Self {
+            config_path: None,
+            config_type: None,
+            db_path: db_path.as_ref().to_path_buf(),
+            user_agent: None,
+            connect_timeout: None,
+            read_timeout: None,
+            timeout: None,
+            proxies: Vec::new(),
+            root_certificates: Vec::new(),
+            client_certificate: None,
+            http2_prior_knowledge: false,
+            max_redirects: 10,
+            gzip: true,
+            deflate: true,
+            brotli: true,
+            zstd: true,
+            concurrency: None,
+            response_method: ResponseMethod::default(),
+            notify_method: NotifyMethod::default(),
+            lock_path: None,
+            lockfile: None,
+            fetch_mode: FetchMode::default(),
+            auto_prune: false,
+            quota: Quota::default(),
+            ttl: None,
+            keep_versions: 0,
+            transactional: false,
+            compression: Compression::None,
+            encryption_method: EncryptionMethod::None,
+            key_provider: None,
+        }
+    }
+
+    /// Set the `User-Agent` header sent with every request
+    pub fn user_agent<S: Into<String>>(mut self, user_agent: S) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Set the timeout for establishing a connection
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the timeout for each read of the response
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the timeout for a whole request (entries can override it with `RequestSpec::timeout`)
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Send all requests through a proxy (`http://`, `https://` or `socks5://` url)
+    pub fn proxy<S: Into<String>>(mut self, proxy: S) -> Self {
+        self.proxies.push(proxy.into());
+        self
+    }
+
+    /// Trust an additional root certificate (PEM, or DER if the file ends in `.der`)
+    pub fn add_root_certificate<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.root_certificates.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Authenticate with a client certificate and its PKCS#8 private key (both PEM)
+    pub fn client_certificate<P: AsRef<Path>>(mut self, cert: P, key: P) -> Self {
+        self.client_certificate = Some((cert.as_ref().to_path_buf(), key.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Only use HTTP/2, without upgrading from HTTP/1
+    pub fn http2_prior_knowledge(mut self, enable: bool) -> Self {
+        self.http2_prior_knowledge = enable;
+        self
+    }
+
+    /// Set the maximum number of redirects to follow (by default 10)
+    pub fn max_redirects(mut self, max_redirects: usize) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// Accept gzip encoded responses (enabled by default)
+    pub fn gzip(mut self, enable: bool) -> Self {
+        self.gzip = enable;
+        self
+    }
+
+    /// Accept deflate encoded responses (enabled by default)
+    pub fn deflate(mut self, enable: bool) -> Self {
+        self.deflate = enable;
+        self
+    }
+
+    /// Accept brotli encoded responses (enabled by default)
+    pub fn brotli(mut self, enable: bool) -> Self {
+        self.brotli = enable;
+        self
+    }
+
+    /// Accept zstd encoded responses (enabled by default)
+    pub fn zstd(mut self, enable: bool) -> Self {
+        self.zstd = enable;
+        self
+    }
+
+    /// Limit the number of entries fetched at the same time
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = Some(concurrency);
+        self
+    }
+
+    /// Set the response method, see `Fetcher::set_response_method`
+    pub fn response_method(mut self, response_method: ResponseMethod) -> Self {
+        self.response_method = response_method;
+        self
+    }
+
+    /// Set the notify method, see `Fetcher::set_notify_method`
+    pub fn notify_method(mut self, notify_method: NotifyMethod) -> Self {
+        self.notify_method = notify_method;
+        self
+    }
+
+    /// Set the path of the lockfile (by default `quickfetch.lock` next to the config file),
+    /// enabling the lockfile unless it's disabled with `lockfile(false)`
+    pub fn lock_path<P: AsRef<Path>>(mut self, lock_path: P) -> Self {
+        self.lock_path = Some(lock_path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Read and write a lockfile (disabled by default, but enabled by setting a `lock_path` or
+    /// `FetchMode::Locked`, in-memory configs need a `lock_path`)
+    pub fn lockfile(mut self, enable: bool) -> Self {
+        self.lockfile = Some(enable);
+        self
+    }
+
+    /// Set the fetch mode, see `Fetcher::set_fetch_mode`
+    pub fn fetch_mode(mut self, fetch_mode: FetchMode) -> Self {
+        self.fetch_mode = fetch_mode;
+        self
+    }
+
+    /// Prune the cached keys that aren't entries anymore after every fetch, see `Fetcher::prune`
+    pub fn auto_prune(mut self, enable: bool) -> Self {
+        self.auto_prune = enable;
+        self
+    }
+
+    /// Set the limits on the size of the cache, see `Fetcher::set_quota`
+    pub fn quota(mut self, quota: Quota) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    /// Set how long cached values stay fresh, see `Fetcher::set_ttl`
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Set the number of previous versions kept for each key, see `Fetcher::set_keep_versions`
+    pub fn keep_versions(mut self, keep_versions: usize) -> Self {
+        self.keep_versions = keep_versions;
+        self
+    }
+
+    /// Store the fetched values all at once or none of them, see `Fetcher::set_transactional`
+    pub fn transactional(mut self, enable: bool) -> Self {
+        self.transactional = enable;
+        self
+    }
+
+    /// Set how cached values are compressed at rest, see `Fetcher::set_compression`
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Set how cached values are encrypted at rest, see `Fetcher::set_encryption_method`
+    pub fn encryption_method(mut self, encryption_method: EncryptionMethod) -> Self {
+        self.encryption_method = encryption_method;
+        self
+    }
+
+    /// Set the provider of the key cached values are encrypted with
+    pub fn key_provider<P: KeyProvider + 'static>(mut self, provider: P) -> Self {
+        self.key_provider = Some(Arc::new(provider));
+        self
+    }
+
+    /// Checks that the settings can be used together
+    pub fn validate(&self) -> Result<()> {
+        if self.notify_method == NotifyMethod::ProgressBar
+            && self.response_method == ResponseMethod::Bytes
+        {
+            bail!("NotifyMethod::ProgressBar requires ResponseMethod::Chunk or ResponseMethod::BytesStream");
+        }
+        if self.fetch_mode == FetchMode::Locked && self.lockfile == Some(false) {
+            bail!("FetchMode::Locked requires the lockfile");
+        }
+        if self.encryption_method != EncryptionMethod::None && self.key_provider.is_none() {
+            bail!(
+                "{:?} encryption requires a key provider",
+                self.encryption_method
+            );
+        }
+        if self.concurrency == Some(0) {
+            bail!("Concurrency must be at least 1");
+        }
+        if self.http2_prior_knowledge && !self.proxies.is_empty() {
+            warn!("HTTP/2 prior knowledge is used through a proxy, which must support it");
+        }
+        if let Some((cert, key)) = &self.client_certificate {
+            for path in [cert, key] {
+                if !path.exists() {
+                    bail!("Client certificate file {} does not exist", path.display());
+                }
+            }
+        }
+        for path in &self.root_certificates {
+            if !path.exists() {
+                bail!("Root certificate file {} does not exist", path.display());
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds the HTTP client
+    ///
+    /// Redirects are always followed by the `Fetcher` itself so credentials can be dropped
+    /// across origins
+    pub fn build_client(&self) -> Result<Client> {
+        let mut builder = Client::builder()
+            .redirect(Policy::none())
+            .gzip(self.gzip)
+            .deflate(self.deflate)
+            .brotli(self.brotli)
+            .zstd(self.zstd);
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.read_timeout {
+            builder = builder.read_timeout(timeout);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        for proxy in &self.proxies {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+        for path in &self.root_certificates {
+            let bytes = std::fs::read(path)?;
+            let certificate = match path.extension().and_then(|x| x.to_str()) {
+                Some("der") => Certificate::from_der(&bytes)?,
+                _ => Certificate::from_pem(&bytes)?,
+            };
+            builder = builder.add_root_certificate(certificate);
+        }
+        if let Some((cert, key)) = &self.client_certificate {
+            let identity = Identity::from_pkcs8_pem(&std::fs::read(cert)?, &std::fs::read(key)?)?;
+            builder = builder.identity(identity);
+        }
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Validates the settings, reads the config file and opens the db
+    pub async fn build<E>(self) -> Result<Fetcher<E>>
+    where
+        E: Entry + Clone + Send + Sync + 'static + for<'de> Deserialize<'de>,
+    {
+        let Some(config_path) = &self.config_path else {
+            bail!("No config file was given, use build_with_config or build_with_entries");
+        };
+        let mode = match self.config_type {
+            Some(mode) => mode,
+            None => {
+                let contents = tokio::fs::read_to_string(config_path).await?;
+                Mode::from_path_or_contents(config_path, &contents)?
+            }
+        };
+        let config = Config::from_file(config_path, mode).await?;
+        Self {
+            config_type: Some(mode),
+            ..self
+        }
+        .build_with_config(config)
+    }
+
+    /// Validates the settings and opens the db, using an in-memory config
+    pub fn build_with_config<E>(self, config: Config<E>) -> Result<Fetcher<E>>
+    where
+        E: Entry + Clone + Send + Sync + 'static + for<'de> Deserialize<'de>,
+    {
+        self.validate()?;
+        let client = self.build_client()?;
+        let entries = config.packages_owned();
+        let db = sled::open(&self.db_path)?;
+        let history = db.open_tree(history::TREE)?;
+        stored::migrate(&db, &history)?;
+        let lockfile = self
+            .lockfile
+            .unwrap_or(self.lock_path.is_some() || self.fetch_mode == FetchMode::Locked);
+        let lock_path = match &self.config_path {
+            _ if !lockfile => None,
+            Some(config_path) => Some(
+                self.lock_path
+                    .unwrap_or_else(|| config_path.with_file_name(LOCKFILE)),
+            ),
+            None => self.lock_path,
+        };
+
+        Ok(Fetcher {
+            entries: Arc::new(entries),
+            meta: db.open_tree(meta::TREE)?,
+            history,
+            keep_versions: self.keep_versions,
+            transactional: self.transactional,
+            compression: self.compression,
+            encryption_method: self.encryption_method,
+            key_provider: self.key_provider,
+            db,
+            db_path: self.db_path,
+            lock_path,
+            fetch_mode: self.fetch_mode,
+            auto_prune: self.auto_prune,
+            quota: self.quota,
+            ttl: self.ttl,
+            config,
+            config_path: self.config_path,
+            config_type: self.config_type.unwrap_or(Mode::Toml),
+            client,
+            custom_client: false,
+            response_method: self.response_method,
+            notify_method: self.notify_method,
+            multi_pb: Arc::new(MultiProgress::new()),
+            s3: Default::default(),
+            mirror_rules: Arc::new(Vec::new()),
+            rewrite_rules: Arc::new(Vec::new()),
+            credential_providers: Arc::new(Vec::new()),
+            max_redirects: self.max_redirects,
+            concurrency: self.concurrency.map(|n| Arc::new(Semaphore::new(n))),
+        })
+    }
+
+    /// Validates the settings and opens the db, using a list of entries as the config
+    pub fn build_with_entries<E>(self, entries: Vec<E>) -> Result<Fetcher<E>>
+    where
+        E: Entry + Clone + Send + Sync + 'static + for<'de> Deserialize<'de>,
+    {
+        self.build_with_config(Config::new(entries))
+    }
+}
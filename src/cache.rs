@@ -0,0 +1,169 @@
+use sled::IVec;
+use std::time::SystemTime;
+
+/// Limits on the size of the cache, enforced by `Fetcher::gc` and after every fetch
+///
+/// - `max_bytes`: The most bytes the cached values may take
+/// - `max_entries`: The most values that may be cached
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Quota {
+    pub max_bytes: Option<u64>,
+    pub max_entries: Option<usize>,
+}
+
+impl Quota {
+    /// A quota without limits
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the most bytes the cached values may take
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Set the most values that may be cached
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Returns true if the quota has no limits
+    pub fn is_unlimited(&self) -> bool {
+        self.max_bytes.is_none() && self.max_entries.is_none()
+    }
+
+    /// Returns true if a cache of this size is over the quota
+    pub fn is_exceeded(&self, bytes: u64, entries: usize) -> bool {
+        self.max_bytes.is_some_and(|x| bytes > x) || self.max_entries.is_some_and(|x| entries > x)
+    }
+}
+
+/// Statistics of the cache, returned by `Fetcher::stats`
+///
+/// - `entries`: The number of cached values
+/// - `bytes`: The bytes the cached values take
+/// - `raw_bytes`: The bytes the cached values would take uncompressed, see `Compression`
+/// - `compressed`: The number of compressed values
+/// - `encrypted`: The number of encrypted values, see `EncryptionMethod`
+/// - `pinned`: The number of pinned values
+/// - `history`: The number of previous versions kept, see `Fetcher::set_keep_versions`
+/// - `history_bytes`: The bytes the previous versions take, which don't count towards the quota
+/// - `size_on_disk`: The size of the db on disk, which only shrinks once sled reclaims space
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub entries: usize,
+    pub bytes: u64,
+    pub raw_bytes: u64,
+    pub compressed: usize,
+    pub encrypted: usize,
+    pub pinned: usize,
+    pub history: usize,
+    pub history_bytes: u64,
+    pub size_on_disk: u64,
+}
+
+impl Stats {
+    /// Returns how many times smaller compression makes the cached values, 1 without compression
+    pub fn compression_ratio(&self) -> f64 {
+        match self.bytes {
+            0 => 1.0,
+            bytes => self.raw_bytes as f64 / bytes as f64,
+        }
+    }
+}
+
+/// A cached value that may be evicted
+pub(crate) struct Candidate {
+    pub key: IVec,
+    pub bytes: u64,
+    pub accessed_at: Option<SystemTime>,
+    pub pinned: bool,
+}
+
+/// Returns the keys to evict to bring the cache within the quota, least recently used first
+///
+/// Values that were never accessed are evicted first and pinned values are never evicted
+pub(crate) fn evict(quota: &Quota, mut candidates: Vec<Candidate>) -> Vec<IVec> {
+    let mut bytes = candidates.iter().map(|x| x.bytes).sum::<u64>();
+    let mut entries = candidates.len();
+    candidates.retain(|x| !x.pinned);
+    candidates.sort_by_key(|x| x.accessed_at);
+
+    let mut evicted = Vec::new();
+    for candidate in candidates {
+        if !quota.is_exceeded(bytes, entries) {
+            break;
+        }
+        bytes -= candidate.bytes;
+        entries -= 1;
+        evicted.push(candidate.key);
+    }
+    evicted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn candidate(key: &str, bytes: u64, accessed: Option<u64>, pinned: bool) -> Candidate {
+        Candidate {
+            key: IVec::from(key),
+            bytes,
+            accessed_at: accessed.map(|x| SystemTime::UNIX_EPOCH + Duration::from_secs(x)),
+            pinned,
+        }
+    }
+
+    fn keys(evicted: Vec<IVec>) -> Vec<String> {
+        evicted
+            .iter()
+            .map(|x| String::from_utf8(x.to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_first() {
+        let candidates = vec![
+            candidate("new", 10, Some(3), false),
+            candidate("old", 10, Some(1), false),
+            candidate("never", 10, None, false),
+            candidate("mid", 10, Some(2), false),
+        ];
+        let quota = Quota::new().with_max_entries(1);
+        assert_eq!(keys(evict(&quota, candidates)), ["never", "old", "mid"]);
+    }
+
+    #[test]
+    fn never_evicts_pinned_values() {
+        // Held values are passed as pinned
+        let candidates = vec![
+            candidate("pinned", 100, None, true),
+            candidate("old", 10, Some(1), false),
+            candidate("new", 10, Some(2), false),
+        ];
+        let quota = Quota::new().with_max_bytes(50);
+        // The pinned value alone is over the quota, everything else goes
+        assert_eq!(keys(evict(&quota, candidates)), ["old", "new"]);
+    }
+
+    #[test]
+    fn evicts_until_both_limits_hold() {
+        let candidates = || {
+            vec![
+                candidate("a", 40, Some(1), false),
+                candidate("b", 10, Some(2), false),
+                candidate("c", 10, Some(3), false),
+            ]
+        };
+        let bytes = Quota::new().with_max_bytes(15);
+        assert_eq!(keys(evict(&bytes, candidates())), ["a", "b"]);
+        let entries = Quota::new().with_max_entries(2);
+        assert_eq!(keys(evict(&entries, candidates())), ["a"]);
+        let both = Quota::new().with_max_bytes(60).with_max_entries(1);
+        assert_eq!(keys(evict(&both, candidates())), ["a", "b"]);
+        assert!(evict(&Quota::new(), candidates()).is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::Result;
+
+/// The zstd level used when an entry asks for compression and the fetcher doesn't set one
+pub const DEFAULT_LEVEL: i32 = 3;
+/// The bytes `Compression::Auto` compresses to decide whether a value is worth compressing
+const SAMPLE: usize = 64 * 1024;
+
+/// How cached values are stored at rest, set with `Fetcher::set_compression`
+///
+/// - `None`: Values are stored as they are
+/// - `Zstd`: Values are compressed with zstd at the level
+/// - `Auto`: Values are compressed with zstd at the level if a sample of them shrinks by at
+///   least a tenth, so already compressed archives aren't compressed again
+///
+/// A value is only stored compressed if that makes it smaller. An entry can override the
+/// setting with `Entry::compress`, and values are read back the same whatever the setting was
+/// when they were stored
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd(i32),
+    Auto(i32),
+}
+
+impl Compression {
+    fn level(&self) -> Option<i32> {
+        match self {
+            Compression::None => None,
+            Compression::Zstd(level) | Compression::Auto(level) => Some(*level),
+        }
+    }
+}
+
+/// Returns a value as it's stored and whether it was compressed, `compress` is the entry's
+/// preference if it has one
+pub(crate) fn encode(
+    compression: Compression,
+    compress: Option<bool>,
+    value: Vec<u8>,
+) -> Result<(Vec<u8>, bool)> {
+    let level = match (compress, compression) {
+        (Some(false), _) | (None, Compression::None) => return Ok((value, false)),
+        (Some(true), _) => compression.level().unwrap_or(DEFAULT_LEVEL),
+        (None, Compression::Zstd(level)) => level,
+        (None, Compression::Auto(level)) => {
+            let sample = &value[..value.len().min(SAMPLE)];
+            if zstd::bulk::compress(sample, 1)?.len() * 10 > sample.len() * 9 {
+                return Ok((value, false));
+            }
+            level
+        }
+    };
+    let compressed = zstd::bulk::compress(&value, level)?;
+    match compressed.len() < value.len() {
+        true => Ok((compressed, true)),
+        false => Ok((value, false)),
+    }
+}
+
+/// Returns a compressed value as it was before it was compressed
+pub(crate) fn decode(compressed: &[u8]) -> Result<Vec<u8>> {
+    Ok(zstd::stream::decode_all(compressed)?)
+}
+
+/// Returns the size of a compressed value before it was compressed
+pub(crate) fn raw_len(compressed: &[u8]) -> Result<u64> {
+    match zstd::zstd_safe::get_frame_content_size(compressed) {
+        Ok(Some(len)) => Ok(len),
+        _ => Ok(decode(compressed)?.len() as u64),
+    }
+}
@@ -0,0 +1,211 @@
+use aes_gcm::Aes256Gcm;
+use anyhow::{anyhow, bail, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use std::fmt::{Debug, Formatter};
+
+/// The length of the nonce stored after the method, both ciphers use 96-bit nonces
+const NONCE_LEN: usize = 12;
+
+/// The AEAD cached values are encrypted with, set with `Fetcher::set_encryption_method`
+///
+/// - `None`: Values are stored as they are
+/// - `ChaCha20Poly1305`: ChaCha20-Poly1305, fast without AES hardware support
+/// - `Aes256Gcm`: AES-256-GCM
+///
+/// Both take a 256-bit key from the `KeyProvider`. Each value is encrypted with a random nonce
+/// and bound to its key and header, so values can't be swapped between keys or have their
+/// header changed without failing to decrypt.
+/// Values are decrypted with the method they were encrypted with whatever the setting is
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum EncryptionMethod {
+    #[default]
+    None,
+    ChaCha20Poly1305,
+    Aes256Gcm,
+}
+
+impl EncryptionMethod {
+    /// Returns the id stored before the nonce, which `decrypt` reads the method from
+    fn id(&self) -> u8 {
+        match self {
+            EncryptionMethod::None => 0,
+            EncryptionMethod::ChaCha20Poly1305 => 1,
+            EncryptionMethod::Aes256Gcm => 2,
+        }
+    }
+}
+
+/// Provides the 256-bit key cached values are encrypted with
+///
+/// The key is asked for whenever a value is stored or read, so a provider backed by a keyring
+/// or a KMS should keep it once it has it
+pub trait KeyProvider: Debug + Send + Sync {
+    /// Returns the key
+    fn key(&self) -> Result<[u8; 32]>;
+}
+
+/// A fixed key
+#[derive(Clone)]
+pub struct StaticKey {
+    key: [u8; 32],
+}
+
+impl StaticKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self { key }
+    }
+
+    /// The key as 64 hex digits
+    pub fn from_hex(key: &str) -> Result<Self> {
+        Ok(Self::new(parse_hex(key)?))
+    }
+}
+
+impl Debug for StaticKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StaticKey").finish_non_exhaustive()
+    }
+}
+
+impl KeyProvider for StaticKey {
+    fn key(&self) -> Result<[u8; 32]> {
+        Ok(self.key)
+    }
+}
+
+/// A key read from an environment variable as 64 hex digits (`QUICKFETCH_KEY` by default)
+#[derive(Debug, Clone)]
+pub struct EnvKey {
+    var: String,
+}
+
+impl Default for EnvKey {
+    fn default() -> Self {
+        Self::new("QUICKFETCH_KEY")
+    }
+}
+
+impl EnvKey {
+    pub fn new<S: Into<String>>(var: S) -> Self {
+        Self { var: var.into() }
+    }
+}
+
+impl KeyProvider for EnvKey {
+    fn key(&self) -> Result<[u8; 32]> {
+        let key = std::env::var(&self.var).map_err(|_| anyhow!("{} is not set", self.var))?;
+        parse_hex(&key).map_err(|e| anyhow!("{}: {}", self.var, e))
+    }
+}
+
+fn parse_hex(key: &str) -> Result<[u8; 32]> {
+    let bytes = hex::decode(key.trim()).map_err(|e| anyhow!("Invalid key: {}", e))?;
+    match <[u8; 32]>::try_from(bytes) {
+        Ok(key) => Ok(key),
+        Err(bytes) => bail!("Invalid key: {} bytes instead of 32", bytes.len()),
+    }
+}
+
+/// Encrypts a value bound to the associated data, as the method, the nonce and the ciphertext
+pub(crate) fn encrypt(
+    method: EncryptionMethod,
+    provider: &dyn KeyProvider,
+    aad: &[u8],
+    value: &[u8],
+) -> Result<Vec<u8>> {
+    let payload = Payload { msg: value, aad };
+    let (nonce, ciphertext) = match method {
+        EncryptionMethod::None => return Ok(value.to_vec()),
+        EncryptionMethod::ChaCha20Poly1305 => {
+            let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let cipher = ChaCha20Poly1305::new(&provider.key()?.into());
+            (nonce, cipher.encrypt(&nonce, payload))
+        }
+        EncryptionMethod::Aes256Gcm => {
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let cipher = Aes256Gcm::new(&provider.key()?.into());
+            (nonce, cipher.encrypt(&nonce, payload))
+        }
+    };
+    let ciphertext = ciphertext.map_err(|_| anyhow!("Failed to encrypt"))?;
+
+    let mut stored = vec![method.id()];
+    stored.extend_from_slice(&nonce);
+    stored.extend_from_slice(&ciphertext);
+    Ok(stored)
+}
+
+/// Decrypts a value bound to the associated data, failing if it was tampered with or the key
+/// is wrong
+pub(crate) fn decrypt(provider: &dyn KeyProvider, aad: &[u8], stored: &[u8]) -> Result<Vec<u8>> {
+    let Some((&id, rest)) = stored.split_first() else {
+        bail!("Truncated encrypted value");
+    };
+    if rest.len() < NONCE_LEN {
+        bail!("Truncated encrypted value");
+    }
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let payload = Payload {
+        msg: ciphertext,
+        aad,
+    };
+    let value = match id {
+        1 => ChaCha20Poly1305::new(&provider.key()?.into()).decrypt(nonce.into(), payload),
+        2 => Aes256Gcm::new(&provider.key()?.into()).decrypt(nonce.into(), payload),
+        _ => bail!("Unknown encryption method {}", id),
+    };
+    value.map_err(|_| anyhow!("Failed to decrypt, the key is wrong or the value was tampered with"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stored::Header;
+
+    const ENCRYPTED: Header = Header {
+        compressed: false,
+        encrypted: true,
+    };
+
+    #[test]
+    fn round_trips_with_both_methods() {
+        let key = StaticKey::new([7; 32]);
+        for method in [
+            EncryptionMethod::ChaCha20Poly1305,
+            EncryptionMethod::Aes256Gcm,
+        ] {
+            let aad = ENCRYPTED.aad(b"key");
+            let stored = encrypt(method, &key, &aad, b"value").unwrap();
+            assert_eq!(stored[0], method.id());
+            assert_eq!(decrypt(&key, &aad, &stored).unwrap(), b"value");
+            // A random nonce makes every encryption different
+            assert_ne!(stored, encrypt(method, &key, &aad, b"value").unwrap());
+        }
+    }
+
+    #[test]
+    fn fails_when_the_key_header_or_row_key_changes() {
+        let key = StaticKey::new([7; 32]);
+        for method in [
+            EncryptionMethod::ChaCha20Poly1305,
+            EncryptionMethod::Aes256Gcm,
+        ] {
+            let aad = ENCRYPTED.aad(b"key");
+            let stored = encrypt(method, &key, &aad, b"value").unwrap();
+
+            assert!(decrypt(&StaticKey::new([8; 32]), &aad, &stored).is_err());
+            let compressed = Header {
+                compressed: true,
+                ..ENCRYPTED
+            };
+            assert!(decrypt(&key, &compressed.aad(b"key"), &stored).is_err());
+            assert!(decrypt(&key, &ENCRYPTED.aad(b"other"), &stored).is_err());
+
+            let mut tampered = stored.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(decrypt(&key, &aad, &tampered).is_err());
+            assert!(decrypt(&key, &aad, &stored[..NONCE_LEN]).is_err());
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use anyhow::{anyhow, bail, Result};
+use futures::future::{join_all, BoxFuture, Shared};
+use futures::FutureExt;
+use quickfetch_traits::{Entry, EntryKey};
+use std::future::Future;
+use std::sync::Arc;
+
+/// The dependency graph of entries, each entry's dependencies by index
+pub type Graph = Vec<Vec<usize>>;
+
+/// Builds the dependency graph of the entries
+///
+/// Also returns the dependencies that aren't entries, as the index of the entry depending on
+/// them and their key
+pub fn graph<E: Entry>(entries: &[E]) -> (Graph, Vec<(usize, String)>) {
+    let keys = entries.iter().map(|x| x.key().bytes()).collect::<Vec<_>>();
+    let mut missing = Vec::new();
+    let graph = entries
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            entry
+                .depends()
+                .into_iter()
+                .filter_map(|dep| match keys.iter().position(|x| *x == dep.bytes()) {
+                    Some(dep) => Some(dep),
+                    None => {
+                        missing.push((index, dep.to_string()));
+                        None
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    (graph, missing)
+}
+
+/// Returns a dependency cycle, starting and ending with the same entry, if there is one
+pub fn find_cycle(graph: &Graph) -> Option<Vec<usize>> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum State {
+        New,
+        Visiting,
+        Done,
+    }
+
+    fn visit(
+        node: usize,
+        graph: &Graph,
+        state: &mut [State],
+        path: &mut Vec<usize>,
+    ) -> Option<Vec<usize>> {
+        state[node] = State::Visiting;
+        path.push(node);
+        for &dep in &graph[node] {
+            match state[dep] {
+                State::Visiting => {
+                    let start = path.iter().position(|x| *x == dep).unwrap_or(0);
+                    let mut cycle = path[start..].to_vec();
+                    cycle.push(dep);
+                    return Some(cycle);
+                }
+                State::New => {
+                    if let Some(cycle) = visit(dep, graph, state, path) {
+                        return Some(cycle);
+                    }
+                }
+                State::Done => {}
+            }
+        }
+        path.pop();
+        state[node] = State::Done;
+        None
+    }
+
+    let mut state = vec![State::New; graph.len()];
+    (0..graph.len()).find_map(|node| match state[node] {
+        State::New => visit(node, graph, &mut state, &mut Vec::new()),
+        _ => None,
+    })
+}
+
+/// Builds the dependency graph and checks that every dependency exists and there are no cycles
+pub fn check<E: Entry>(entries: &[E]) -> Result<Graph> {
+    let (graph, missing) = graph(entries);
+    if let Some((index, dep)) = missing.first() {
+        bail!(
+            "{} depends on {}, which isn't an entry",
+            entries[*index].key(),
+            dep
+        );
+    }
+    if let Some(cycle) = find_cycle(&graph) {
+        let cycle = cycle
+            .iter()
+            .map(|x| entries[*x].key().to_string())
+            .collect::<Vec<_>>();
+        bail!("Dependency cycle: {}", cycle.join(" -> "));
+    }
+    Ok(graph)
+}
+
+/// Returns the roots and everything they depend on, with dependencies before their dependents
+///
+/// The graph must not have cycles
+pub fn closure<I: IntoIterator<Item = usize>>(graph: &Graph, roots: I) -> Vec<usize> {
+    fn visit(node: usize, graph: &Graph, seen: &mut [bool], order: &mut Vec<usize>) {
+        if seen[node] {
+            return;
+        }
+        seen[node] = true;
+        for &dep in &graph[node] {
+            visit(dep, graph, seen, order);
+        }
+        order.push(node);
+    }
+
+    let mut seen = vec![false; graph.len()];
+    let mut order = Vec::new();
+    for root in roots {
+        visit(root, graph, &mut seen, &mut order);
+    }
+    order
+}
+
+/// Runs `work` for every entry of the graph once all of its dependencies succeeded
+///
+/// Entries that don't depend on each other run concurrently. When an entry fails, the entries
+/// depending on it are skipped and the first error is returned. The graph must not have cycles.
+pub async fn run<F, Fut>(graph: &Graph, mut work: F) -> Result<()>
+where
+    F: FnMut(usize) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    type Task = Shared<BoxFuture<'static, Result<(), Arc<anyhow::Error>>>>;
+
+    let mut tasks: Vec<Option<Task>> = vec![None; graph.len()];
+    for node in closure(graph, 0..graph.len()) {
+        let deps = graph[node]
+            .iter()
+            .filter_map(|x| tasks[*x].clone())
+            .collect::<Vec<_>>();
+        let job = work(node);
+        let task = async move {
+            for dep in deps {
+                dep.await?;
+            }
+            match tokio::spawn(job).await {
+                Ok(result) => result.map_err(Arc::new),
+                Err(e) => Err(Arc::new(e.into())),
+            }
+        }
+        .boxed()
+        .shared();
+        tasks[node] = Some(task);
+    }
+
+    for result in join_all(tasks.into_iter().flatten()).await {
+        result.map_err(|e| anyhow!("{:#}", e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::SimplePackage;
+    use std::sync::Mutex;
+
+    fn package(name: &str, depends: &[&str]) -> SimplePackage {
+        SimplePackage::new(name, "1.0", "https://example.com/a.bin")
+            .with_depends(depends.iter().map(|x| x.to_string()).collect())
+    }
+
+    #[test]
+    fn finds_cycles() {
+        assert_eq!(find_cycle(&vec![vec![1], vec![2], vec![]]), None);
+        assert_eq!(
+            find_cycle(&vec![vec![1], vec![2], vec![1]]),
+            Some(vec![1, 2, 1])
+        );
+        assert_eq!(find_cycle(&vec![vec![0]]), Some(vec![0, 0]));
+
+        let entries = [package("a", &["b"]), package("b", &["a"])];
+        let error = check(&entries).unwrap_err();
+        assert_eq!(error.to_string(), "Dependency cycle: a -> b -> a");
+        let error = check(&[package("a", &["c"])]).unwrap_err();
+        assert_eq!(error.to_string(), "a depends on c, which isn't an entry");
+    }
+
+    #[test]
+    fn puts_dependencies_before_their_dependents() {
+        // 0 -> 1 -> 3, 0 -> 2 -> 3, 4 on its own
+        let graph = vec![vec![1, 2], vec![3], vec![3], vec![], vec![]];
+        assert_eq!(closure(&graph, [0]), [3, 1, 2, 0]);
+        assert_eq!(closure(&graph, [2, 4]), [3, 2, 4]);
+        assert_eq!(closure(&graph, [1, 0]), [3, 1, 2, 0]);
+    }
+
+    #[tokio::test]
+    async fn runs_dependencies_first_and_skips_dependents_of_failures() {
+        let graph = vec![vec![1], vec![2], vec![], vec![]];
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let ran = order.clone();
+        run(&graph, move |node| {
+            let ran = ran.clone();
+            async move {
+                ran.lock().unwrap().push(node);
+                Ok(())
+            }
+        })
+        .await
+        .unwrap();
+        let order = order.lock().unwrap().clone();
+        let position = |node| order.iter().position(|x| *x == node).unwrap();
+        assert_eq!(order.len(), 4);
+        assert!(position(2) < position(1) && position(1) < position(0));
+
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let record = ran.clone();
+        let error = run(&graph, move |node| {
+            let record = record.clone();
+            async move {
+                record.lock().unwrap().push(node);
+                match node {
+                    1 => bail!("1 failed"),
+                    _ => Ok(()),
+                }
+            }
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "1 failed");
+        let mut ran = ran.lock().unwrap().clone();
+        ran.sort();
+        assert_eq!(ran, [1, 2, 3]);
+    }
+}
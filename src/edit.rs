@@ -0,0 +1,134 @@
+use anyhow::Result;
+use std::path::Path;
+use toml_edit::{ArrayOfTables, DocumentMut, Item, Table, Value};
+
+/// Merges a freshly serialized TOML config into the existing document
+///
+/// Values that didn't change are left untouched, so comments, ordering and formatting of the
+/// existing file survive. Changed values keep the comments around them, removed keys are
+/// dropped and new keys are appended.
+///
+/// `[[packages]]` tables are matched by package key (`old_keys` and `new_keys` are the keys of
+/// the packages in the existing and the new document, in order) rather than by position, so
+/// inserting or removing a package doesn't touch the tables of the others.
+pub fn merge_toml(
+    existing: &str,
+    new: &str,
+    old_keys: &[Vec<u8>],
+    new_keys: &[Vec<u8>],
+) -> Result<String> {
+    let mut doc = existing.parse::<DocumentMut>()?;
+    let mut new = new.parse::<DocumentMut>()?.as_table().clone();
+
+    let old_packages = doc.get("packages").and_then(Item::as_array_of_tables);
+    let packages = match (old_packages, new.get("packages")) {
+        (Some(old), Some(Item::ArrayOfTables(new))) if old.len() == old_keys.len() => {
+            Some(merge_packages(old, new, old_keys, new_keys))
+        }
+        _ => None,
+    };
+    match packages {
+        Some(packages) => {
+            new.remove("packages");
+            merge_table(doc.as_table_mut(), &new, &["packages"]);
+            doc["packages"] = Item::ArrayOfTables(packages);
+        }
+        None => merge_table(doc.as_table_mut(), &new, &[]),
+    }
+    Ok(doc.to_string())
+}
+
+/// Writes the contents to a temporary file next to `path` and renames it over `path`,
+/// so readers never see a partially written file
+pub fn write_atomic<P: AsRef<Path>>(path: P, contents: &[u8]) -> Result<()> {
+    use std::io::Write;
+
+    let path = path.as_ref();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(contents)?;
+    file.as_file().sync_all()?;
+    if let Ok(metadata) = std::fs::metadata(path) {
+        std::fs::set_permissions(file.path(), metadata.permissions())?;
+    }
+    file.persist(path)?;
+    Ok(())
+}
+
+fn merge_packages(
+    old: &ArrayOfTables,
+    new: &ArrayOfTables,
+    old_keys: &[Vec<u8>],
+    new_keys: &[Vec<u8>],
+) -> ArrayOfTables {
+    let mut merged = ArrayOfTables::new();
+    for (new, key) in new.iter().zip(new_keys) {
+        let table = match old_keys.iter().position(|x| x == key) {
+            Some(index) => {
+                let mut table = old.get(index).cloned().unwrap_or_default();
+                merge_table(&mut table, new, &[]);
+                table
+            }
+            None => new.clone(),
+        };
+        merged.push(table);
+    }
+    merged
+}
+
+/// Merges `new` into `old`, leaving the keys in `keep` alone
+fn merge_table(old: &mut Table, new: &Table, keep: &[&str]) {
+    let removed = old
+        .iter()
+        .map(|(key, _)| key.to_string())
+        .filter(|key| !new.contains_key(key) && !keep.contains(&key.as_str()))
+        .collect::<Vec<_>>();
+    for key in removed {
+        old.remove(&key);
+    }
+    for (key, item) in new.iter() {
+        match old.get_mut(key) {
+            Some(existing) => merge_item(existing, item.clone()),
+            None => {
+                old.insert(key, item.clone());
+            }
+        }
+    }
+}
+
+fn merge_item(old: &mut Item, new: Item) {
+    match (old, new) {
+        (Item::Table(old), Item::Table(new)) => merge_table(old, &new, &[]),
+        (Item::Value(old), Item::Value(new)) => merge_value(old, new),
+        (Item::ArrayOfTables(old), Item::ArrayOfTables(new)) if old.len() == new.len() => {
+            for (old, new) in old.iter_mut().zip(new.iter()) {
+                merge_table(old, new, &[]);
+            }
+        }
+        (old, new) => *old = new,
+    }
+}
+
+fn merge_value(old: &mut Value, new: Value) {
+    if normalized(old) == normalized(&new) {
+        return;
+    }
+    let decor = old.decor().clone();
+    *old = new;
+    *old.decor_mut() = decor;
+}
+
+/// Returns the value formatted without any comments or whitespace of its own
+fn normalized(value: &Value) -> String {
+    let mut value = value.clone();
+    match &mut value {
+        Value::Array(array) => array.fmt(),
+        Value::InlineTable(table) => table.fmt(),
+        _ => {}
+    }
+    value.decor_mut().clear();
+    value.to_string()
+}
@@ -0,0 +1,101 @@
+use quickfetch_traits::{Entry, EntryKey};
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+/// Selects the entries of a `Fetcher` to fetch or write
+///
+/// - `All`: Every entry
+/// - `Keys`: Entries with one of the keys
+/// - `Glob`: Entries whose key matches a glob such as `mufiz-rpm-*`
+/// - `Tag`: Entries with the tag (or group)
+/// - `Predicate`: Entries the closure returns true for
+/// - `Any`: Entries matching any of the filters
+///
+/// `Fetcher::entries` is always the full set of entries, filters only narrow what a
+/// single fetch or write works on.
+pub enum Filter<E: Entry> {
+    All,
+    Keys(Vec<Vec<u8>>),
+    Glob(glob::Pattern),
+    Tag(String),
+    Predicate(Arc<dyn Fn(&E) -> bool + Send + Sync>),
+    Any(Vec<Filter<E>>),
+}
+
+impl<E: Entry> Filter<E> {
+    /// Matches the entry with the key
+    pub fn key(key: E::Key) -> Self {
+        Self::Keys(vec![key.bytes()])
+    }
+
+    /// Matches the entries with the keys
+    pub fn keys<I: IntoIterator<Item = E::Key>>(keys: I) -> Self {
+        Self::Keys(keys.into_iter().map(|x| x.bytes()).collect())
+    }
+
+    /// Matches the entries whose key matches the glob
+    pub fn glob(pattern: &str) -> anyhow::Result<Self> {
+        Ok(Self::Glob(glob::Pattern::new(pattern)?))
+    }
+
+    /// Matches the entries with the tag
+    pub fn tag<S: Into<String>>(tag: S) -> Self {
+        Self::Tag(tag.into())
+    }
+
+    /// Matches the entries the closure returns true for
+    pub fn predicate<F: Fn(&E) -> bool + Send + Sync + 'static>(predicate: F) -> Self {
+        Self::Predicate(Arc::new(predicate))
+    }
+
+    /// Matches the entries matching any of the filters
+    pub fn any(filters: Vec<Filter<E>>) -> Self {
+        Self::Any(filters)
+    }
+
+    /// Returns true if the entry is selected
+    pub fn matches(&self, entry: &E) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::Keys(keys) => keys.contains(&entry.key().bytes()),
+            Filter::Glob(pattern) => pattern.matches(&entry.key().to_string()),
+            Filter::Tag(tag) => entry.tags().contains(tag),
+            Filter::Predicate(predicate) => predicate(entry),
+            Filter::Any(filters) => filters.iter().any(|x| x.matches(entry)),
+        }
+    }
+}
+
+impl<E: Entry> Clone for Filter<E> {
+    fn clone(&self) -> Self {
+        match self {
+            Filter::All => Filter::All,
+            Filter::Keys(keys) => Filter::Keys(keys.clone()),
+            Filter::Glob(pattern) => Filter::Glob(pattern.clone()),
+            Filter::Tag(tag) => Filter::Tag(tag.clone()),
+            Filter::Predicate(predicate) => Filter::Predicate(Arc::clone(predicate)),
+            Filter::Any(filters) => Filter::Any(filters.clone()),
+        }
+    }
+}
+
+impl<E: Entry> Debug for Filter<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Filter::All => write!(f, "All"),
+            Filter::Keys(keys) => f
+                .debug_tuple("Keys")
+                .field(
+                    &keys
+                        .iter()
+                        .map(|x| String::from_utf8_lossy(x))
+                        .collect::<Vec<_>>(),
+                )
+                .finish(),
+            Filter::Glob(pattern) => f.debug_tuple("Glob").field(&pattern.as_str()).finish(),
+            Filter::Tag(tag) => f.debug_tuple("Tag").field(tag).finish(),
+            Filter::Predicate(_) => write!(f, "Predicate(..)"),
+            Filter::Any(filters) => f.debug_tuple("Any").field(filters).finish(),
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use anyhow::{anyhow, bail, Result};
+use std::path::Path;
+use tokio::process::Command;
+
+/// Returns true if the url is a git snapshot url (`git+<repo url>#<rev>`)
+pub fn is_git_url(url: &str) -> bool {
+    url.starts_with("git+")
+}
+
+/// Splits a `git+<repo url>#<rev>` url into the repository url and the rev
+///
+/// The rev defaults to `HEAD` when no fragment is given. A repository or rev starting with
+/// `-` is rejected, as git would read it as an option
+pub fn parse_url(url: &str) -> Result<(&str, &str)> {
+    let rest = url
+        .strip_prefix("git+")
+        .ok_or_else(|| anyhow!("Invalid git url {}, expected git+<repo url>#<rev>", url))?;
+    let (repo, rev) = match rest.split_once('#') {
+        Some((repo, rev)) if !rev.is_empty() => (repo, rev),
+        Some((repo, _)) => (repo, "HEAD"),
+        None => (rest, "HEAD"),
+    };
+    if repo.is_empty() || repo.starts_with('-') || rev.starts_with('-') {
+        bail!(
+            "Invalid git url {}, the repository and rev can't start with -",
+            url
+        );
+    }
+    Ok((repo, rev))
+}
+
+fn is_commit(rev: &str) -> bool {
+    rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+async fn git(args: &[&str], dir: Option<&Path>) -> Result<Vec<u8>> {
+    let mut cmd = Command::new("git");
+    cmd.args(args);
+    if let Some(dir) = dir {
+        cmd.current_dir(dir);
+    }
+    let output = cmd.output().await?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Returns the commit of a git url pinned to one, which resolves without the network
+pub fn pinned_commit(url: &str) -> Option<String> {
+    let (_, rev) = parse_url(url).ok()?;
+    is_commit(rev).then(|| rev.to_lowercase())
+}
+
+/// Resolves the rev of a git url (tag, branch or commit) to the commit it points to
+pub async fn resolve(url: &str) -> Result<String> {
+    let (repo, rev) = parse_url(url)?;
+    if is_commit(rev) {
+        return Ok(rev.to_lowercase());
+    }
+
+    let peeled = format!("{}^{{}}", rev);
+    let output = git(&["ls-remote", "--", repo, rev, &peeled], None).await?;
+    let refs = String::from_utf8(output)?;
+    let mut commit = None;
+    for line in refs.lines() {
+        if let Some((sha, name)) = line.split_once('\t') {
+            // Annotated tags are listed twice, the peeled `^{}` line points at the commit
+            if name.ends_with("^{}") || commit.is_none() {
+                commit = Some(sha.to_string());
+            }
+        }
+    }
+    commit.ok_or_else(|| anyhow!("Could not resolve {} in {}", rev, repo))
+}
+
+/// Fetches a shallow snapshot of a commit and returns a tarball of its tree
+pub async fn snapshot(url: &str, commit: &str) -> Result<Vec<u8>> {
+    let (repo, rev) = parse_url(url)?;
+    if !is_commit(commit) {
+        bail!("Invalid commit {} for {}", commit, url);
+    }
+    let dir = tempfile::tempdir()?;
+    let path = Some(dir.path());
+
+    git(&["init", "-q", "--bare"], path).await?;
+    // Servers only allow shallow fetches of advertised refs, so fetch by name first
+    let fetched = if is_commit(rev) {
+        git(&["fetch", "-q", "--depth", "1", "--", repo, commit], path).await
+    } else {
+        git(&["fetch", "-q", "--depth", "1", "--", repo, rev], path).await
+    };
+    if fetched.is_err() || git(&["cat-file", "-e", commit], path).await.is_err() {
+        debug!("Shallow fetch of {} failed, fetching full history", url);
+        git(
+            &["fetch", "-q", "--", repo, "+refs/*:refs/remotes/origin/*"],
+            path,
+        )
+        .await?;
+    }
+
+    git(&["archive", "--format=tar", commit], path).await
+}
+
+/// Unpacks a tarball created by `snapshot` into a directory
+pub fn unpack(tarball: &[u8], dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    tar::Archive::new(tarball).unpack(dir)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn run(dir: &Path, args: &[&str]) -> String {
+        let output = std::process::Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(["-c", "commit.gpgsign=false", "-c", "tag.gpgsign=false"])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    /// Creates a bare repository with a `main` branch of two commits, the first tagged `v1`
+    /// with an annotated tag, returning its `git+file://` url and the two commits
+    fn bare_repo(dir: &Path) -> (String, String, String) {
+        let work = dir.join("work");
+        std::fs::create_dir(&work).unwrap();
+        run(&work, &["init", "-q"]);
+        run(&work, &["checkout", "-q", "-b", "main"]);
+        std::fs::write(work.join("file.txt"), "one").unwrap();
+        run(&work, &["add", "."]);
+        run(&work, &["commit", "-q", "-m", "one"]);
+        run(&work, &["tag", "-a", "v1", "-m", "v1"]);
+        let first = run(&work, &["rev-parse", "HEAD"]);
+        std::fs::write(work.join("file.txt"), "two").unwrap();
+        run(&work, &["commit", "-q", "-am", "two"]);
+        let second = run(&work, &["rev-parse", "HEAD"]);
+
+        let bare = dir.join("repo.git");
+        run(dir, &["init", "-q", "--bare", bare.to_str().unwrap()]);
+        run(&work, &["push", "-q", bare.to_str().unwrap(), "main", "v1"]);
+        (format!("git+file://{}", bare.display()), first, second)
+    }
+
+    #[tokio::test]
+    async fn resolves_branches_tags_and_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, first, second) = bare_repo(dir.path());
+        assert_eq!(resolve(&format!("{}#main", url)).await.unwrap(), second);
+        assert_eq!(resolve(&format!("{}#v1", url)).await.unwrap(), first);
+        let pinned = format!("{}#{}", url, first.to_uppercase());
+        assert_eq!(resolve(&pinned).await.unwrap(), first);
+        assert!(resolve(&format!("{}#missing", url)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn snapshots_and_unpacks_a_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, first, second) = bare_repo(dir.path());
+        for (rev, commit, contents) in [("main", &second, "two"), ("v1", &first, "one")] {
+            let tarball = snapshot(&format!("{}#{}", url, rev), commit).await.unwrap();
+            let out = dir.path().join(format!("out-{}", rev));
+            unpack(&tarball, &out).unwrap();
+            assert_eq!(
+                std::fs::read_to_string(out.join("file.txt")).unwrap(),
+                contents
+            );
+        }
+        // A commit that isn't a branch or tag head
+        let tarball = snapshot(&format!("{}#{}", url, first), &first)
+            .await
+            .unwrap();
+        let out = dir.path().join("out-commit");
+        unpack(&tarball, &out).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(out.join("file.txt")).unwrap(),
+            "one"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_options_as_repository_or_rev() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker: PathBuf = dir.path().join("pwned");
+        let url = format!("git+--upload-pack=touch {}#main", marker.display());
+        assert!(parse_url(&url).is_err());
+        assert!(resolve(&url).await.is_err());
+        assert!(parse_url("git+https://example.com/repo.git#--output=x").is_err());
+        assert!(snapshot("git+https://example.com/repo.git#main", "--help")
+            .await
+            .is_err());
+        assert!(!marker.exists());
+    }
+}
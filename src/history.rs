@@ -0,0 +1,75 @@
+use crate::meta::Record;
+use anyhow::{bail, Result};
+
+/// Name of the sled tree holding the previous values of each key
+pub(crate) const TREE: &str = "history";
+
+/// A cached version of a key, returned by `Fetcher::versions`
+///
+/// - `id`: Identifies the version, to roll back to it or write it
+/// - `current`: Whether it's the version currently cached
+/// - `record`: What was fetched, including its version and when
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub id: u64,
+    pub current: bool,
+    pub record: Record,
+}
+
+/// Returns the prefix of the history keys of a key
+///
+/// The key is preceded by its length so that no key's prefix is another key's prefix
+pub(crate) fn prefix(key: &[u8]) -> Vec<u8> {
+    let mut prefix = (key.len() as u32).to_be_bytes().to_vec();
+    prefix.extend_from_slice(key);
+    prefix
+}
+
+/// Returns the history key of a version, versions of a key sort by id
+pub(crate) fn key(key: &[u8], id: u64) -> Vec<u8> {
+    let mut key = prefix(key);
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+/// Returns the id of a version from its history key
+pub(crate) fn id(key: &[u8]) -> u64 {
+    let mut id = [0; 8];
+    id.copy_from_slice(&key[key.len() - 8..]);
+    u64::from_be_bytes(id)
+}
+
+/// Returns the key and the id of a version from its history key
+pub(crate) fn split(key: &[u8]) -> Option<(&[u8], u64)> {
+    let (len, rest) = key.split_first_chunk::<4>()?;
+    let len = u32::from_be_bytes(*len) as usize;
+    if rest.len() != len + 8 {
+        return None;
+    }
+    Some((&rest[..len], id(key)))
+}
+
+/// Stores a previous value with its record
+pub(crate) fn encode(record: &Record, value: &[u8]) -> Vec<u8> {
+    let record = record.to_bytes();
+    let mut bytes = (record.len() as u32).to_be_bytes().to_vec();
+    bytes.extend_from_slice(&record);
+    bytes.extend_from_slice(value);
+    bytes
+}
+
+/// Reads a previous value and its record
+pub(crate) fn decode(bytes: &[u8]) -> Result<(Record, &[u8])> {
+    let Some((len, rest)) = bytes.split_first_chunk::<4>() else {
+        bail!("Truncated history entry");
+    };
+    let len = u32::from_be_bytes(*len) as usize;
+    if rest.len() < len {
+        bail!("Truncated history entry");
+    }
+    let (record, value) = rest.split_at(len);
+    match Record::from_bytes(record) {
+        Some(record) => Ok((record, value)),
+        None => bail!("Unreadable history record"),
+    }
+}
@@ -0,0 +1,239 @@
+use crate::package::Mode;
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Where the final values of a package came from when config files are layered
+///
+/// - `defined_in`: The file that first defined the package
+/// - `fields`: The file that last set each field of the package
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Provenance {
+    pub defined_in: PathBuf,
+    pub fields: BTreeMap<String, PathBuf>,
+    /// The layers and indices in their `packages` of the tables making up the package
+    pub(crate) tables: Vec<(usize, usize)>,
+}
+
+impl Provenance {
+    /// Returns the file that last set the field
+    pub fn field(&self, field: &str) -> Option<&Path> {
+        self.fields.get(field).map(PathBuf::as_path)
+    }
+}
+
+/// A config file read while resolving includes
+#[derive(Debug, Clone)]
+pub struct Layer {
+    pub path: PathBuf,
+    pub mode: Mode,
+    pub contents: String,
+    pub value: Value,
+}
+
+/// Reads a config file and the files it includes, returning them in the order they apply
+///
+/// Included files are relative to the file including them and may be glob patterns (matches
+/// are sorted by path). Each file is preceded by its includes, so it overrides them. Including
+/// a file that doesn't exist is an error, a pattern matching nothing is not.
+pub fn load<P: AsRef<Path>>(path: P, mode: Option<Mode>) -> Result<Vec<Layer>> {
+    let mut layers = Vec::new();
+    load_into(path.as_ref(), mode, &mut Vec::new(), &mut layers)?;
+    Ok(layers)
+}
+
+fn load_into(
+    path: &Path,
+    mode: Option<Mode>,
+    stack: &mut Vec<PathBuf>,
+    layers: &mut Vec<Layer>,
+) -> Result<()> {
+    let canonical = path
+        .canonicalize()
+        .with_context(|| format!("Could not read {}", path.display()))?;
+    if stack.contains(&canonical) {
+        bail!("Include cycle through {}", path.display());
+    }
+    let contents = std::fs::read_to_string(path)?;
+    let mode = match mode {
+        Some(mode) => mode,
+        None => Mode::from_path_or_contents(path, &contents)?,
+    };
+    let value = parse(&contents, mode).with_context(|| format!("Invalid {}", path.display()))?;
+
+    stack.push(canonical);
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    for include in includes(&value, path)? {
+        if is_pattern(&include) {
+            let pattern = dir.join(&include);
+            let pattern = pattern
+                .to_str()
+                .ok_or_else(|| anyhow!("Invalid include {}", include))?;
+            for matched in glob::glob(pattern)? {
+                let matched = matched?;
+                // A pattern may match the including file, which is simply skipped
+                if !stack.contains(&matched.canonicalize()?) {
+                    load_into(&matched, None, stack, layers)?;
+                }
+            }
+        } else {
+            load_into(&dir.join(&include), None, stack, layers)?;
+        }
+    }
+    stack.pop();
+
+    layers.push(Layer {
+        path: path.to_path_buf(),
+        mode,
+        contents,
+        value,
+    });
+    Ok(())
+}
+
+/// Parses a config file of any format into a generic value
+pub fn parse(contents: &str, mode: Mode) -> Result<Value> {
+    Ok(match mode {
+        Mode::Json => serde_json::from_str(contents)?,
+        Mode::Toml => toml::from_str(contents)?,
+        Mode::Yaml => serde_yaml::from_str(contents)?,
+        Mode::Ron => ron::from_str(contents)?,
+    })
+}
+
+/// Serializes a generic value as a config file of any format
+pub fn to_string(value: &Value, mode: Mode) -> Result<String> {
+    Ok(match mode {
+        Mode::Json => serde_json::to_string_pretty(value)?,
+        Mode::Toml => toml::to_string_pretty(value)?,
+        Mode::Yaml => serde_yaml::to_string(value)?,
+        Mode::Ron => ron::ser::to_string_pretty(value, ron::ser::PrettyConfig::default())?,
+    })
+}
+
+fn includes(value: &Value, path: &Path) -> Result<Vec<String>> {
+    match value.get("include") {
+        None => Ok(Vec::new()),
+        Some(Value::Array(includes)) => includes
+            .iter()
+            .map(|x| {
+                x.as_str().map(String::from).ok_or_else(|| {
+                    anyhow!("`include` in {} must be a list of paths", path.display())
+                })
+            })
+            .collect(),
+        Some(_) => bail!("`include` in {} must be a list of paths", path.display()),
+    }
+}
+
+fn is_pattern(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+/// Merges the layers into a single config value
+///
+/// A package overrides the fields of an earlier package when the earlier package keeps its key
+/// with the fields applied, so a layer only needs the fields making up the key and the ones it
+/// changes. Other packages are added in order. Vars and credentials are overridden by name and
+/// host, and the rewrite rules of later layers are tried first. The `include` list of the last
+/// layer is kept.
+///
+/// Packages are merged before `${...}` references are resolved.
+///
+/// `key` returns the key of a package, or `None` if it isn't a complete package.
+pub fn merge<F>(layers: &[Layer], key: F) -> Result<(Value, HashMap<Vec<u8>, Provenance>)>
+where
+    F: Fn(&Value) -> Option<Vec<u8>>,
+{
+    let mut packages: Vec<(Vec<u8>, Map<String, Value>)> = Vec::new();
+    let mut provenance = HashMap::new();
+    let mut rewrites = Vec::new();
+    let mut credentials = Map::new();
+    let mut vars = Map::new();
+
+    for (layer_index, layer) in layers.iter().enumerate() {
+        let Some(root) = layer.value.as_object() else {
+            bail!("{} is not a config", layer.path.display());
+        };
+        let layer_packages = match root.get("packages") {
+            Some(Value::Array(packages)) => packages.as_slice(),
+            Some(_) => bail!("`packages` in {} must be a list", layer.path.display()),
+            None => &[],
+        };
+        for (index, package) in layer_packages.iter().enumerate() {
+            let Some(fields) = package.as_object() else {
+                bail!(
+                    "Package {} in {} is not a table",
+                    index,
+                    layer.path.display()
+                );
+            };
+            let found = packages.iter().position(|(existing_key, existing)| {
+                let mut merged = existing.clone();
+                merged.extend(fields.clone());
+                key(&Value::Object(merged)).as_ref() == Some(existing_key)
+            });
+            let package_key = match found {
+                Some(index) => {
+                    let (package_key, existing) = &mut packages[index];
+                    existing.extend(fields.clone());
+                    package_key.clone()
+                }
+                None => {
+                    let Some(package_key) = key(package) else {
+                        bail!(
+                            "Package {} in {} doesn't override an earlier package and is incomplete",
+                            index,
+                            layer.path.display()
+                        );
+                    };
+                    packages.push((package_key.clone(), fields.clone()));
+                    package_key
+                }
+            };
+            let record = provenance.entry(package_key).or_insert_with(|| Provenance {
+                defined_in: layer.path.clone(),
+                ..Default::default()
+            });
+            record.tables.push((layer_index, index));
+            for field in fields.keys() {
+                record.fields.insert(field.clone(), layer.path.clone());
+            }
+        }
+
+        if let Some(Value::Array(layer_rewrites)) = root.get("rewrites") {
+            rewrites.splice(0..0, layer_rewrites.iter().cloned());
+        }
+        if let Some(Value::Object(layer_credentials)) = root.get("credentials") {
+            credentials.extend(layer_credentials.clone());
+        }
+        if let Some(Value::Object(layer_vars)) = root.get("vars") {
+            vars.extend(layer_vars.clone());
+        }
+    }
+
+    let mut root = Map::new();
+    if let Some(include) = layers.last().and_then(|x| x.value.get("include")) {
+        root.insert("include".to_string(), include.clone());
+    }
+    root.insert(
+        "packages".to_string(),
+        Value::Array(
+            packages
+                .into_iter()
+                .map(|(_, x)| Value::Object(x))
+                .collect(),
+        ),
+    );
+    if !rewrites.is_empty() {
+        root.insert("rewrites".to_string(), Value::Array(rewrites));
+    }
+    if !vars.is_empty() {
+        root.insert("vars".to_string(), Value::Object(vars));
+    }
+    if !credentials.is_empty() {
+        root.insert("credentials".to_string(), Value::Object(credentials));
+    }
+    Ok((Value::Object(root), provenance))
+}
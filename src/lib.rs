@@ -1,36 +1,125 @@
 #![doc = include_str!("../README.md")]
 #[macro_use]
 extern crate log;
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
+use auth::{Credential, CredentialProvider};
 pub use bincode;
+use builder::FetcherBuilder;
 use bytes::Bytes;
+use cache::{Candidate, Quota, Stats};
+use compress::Compression;
+use crypt::{EncryptionMethod, KeyProvider};
+use filter::Filter;
 use futures::future::join_all;
 use futures::StreamExt;
+use history::Version;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use lock::{LockedEntry, Lockfile, LOCKFILE};
+use meta::Record;
+use mirror::MirrorRule;
 use notify::{Config as NConfig, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use package::{Config, Mode};
+use plan::{Change, Plan};
 pub use pretty_env_logger;
 pub use quickfetch_traits as traits;
-use quickfetch_traits::{Entry, EntryKey, EntryValue};
-use reqwest::{Client, Response};
+use quickfetch_traits::{Entry, EntryKey, EntryValue, RequestSpec};
+#[cfg(feature = "unstable")]
+use rayon::prelude::*;
+use report::{Commit, FetchReport, Outcome};
+use reqwest::header::{ETAG, LOCATION};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use rewrite::RewriteRule;
+use s3::S3Config;
 use serde::Deserialize;
-use sled::Db;
+use sha2::{Digest, Sha256};
+use sled::transaction::{TransactionError, Transactional};
+use sled::{Batch, Db, IVec, Tree};
+use std::collections::{BTreeMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use stored::Header;
 use tokio::fs::create_dir;
 use tokio::sync::mpsc::{channel, Receiver};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
 use url::Url;
+use verify::{Fix, Issue, Repair, VerifyReport};
+/// Provides per-host credentials from netrc, the environment or the config
+pub mod auth;
+/// Provides a builder to configure a Fetcher and its HTTP client
+pub mod builder;
+/// Provides the cache quota and statistics
+pub mod cache;
+/// Provides compression of cached values at rest
+pub mod compress;
+/// Provides encryption of cached values at rest and the keys it uses
+pub mod crypt;
+/// Provides dependencies between entries and running them in dependency order
+pub mod deps;
+/// Provides format-preserving edits and atomic writes of config files
+pub mod edit;
+/// Provides filters selecting the entries to fetch or write
+pub mod filter;
+/// Provides shallow snapshots of git repositories for `git+<repo url>#<rev>` urls
+pub mod git;
+/// Provides the previous versions kept for each key
+pub mod history;
+/// Provides config includes and the layering of config files
+pub mod layer;
+/// Provides the lints checked by `Config::validate`
+pub mod lint;
+/// Provides the lockfile recording what each entry resolved to
+pub mod lock;
+/// Provides the records kept about each cached response
+pub mod meta;
+/// Provides global mirror rules used to fail over between urls
+pub mod mirror;
 /// Provides different types of packages that can be used
 pub mod package;
+/// Provides the plan of what a fetch would do
+pub mod plan;
+/// Provides the report of what a fetch did
+pub mod report;
+/// Provides url rewrite rules applied before requesting
+pub mod rewrite;
+/// Provides resolving and signing of `s3://bucket/key` urls
+pub mod s3;
+/// Provides the header every stored value starts with, saying how it's stored
+pub mod stored;
+/// Provides a minimal HTTP server for tests
+#[cfg(test)]
+mod testing;
 /// Provides structures that can be used as a Key and Value for Fetcher
 pub mod val;
+/// Provides `${var}` interpolation of package fields
+pub mod vars;
+/// Provides the report of verifying the cache and how to repair it
+pub mod verify;
 
 /// Provides all the common types to use with Fetcher
 pub mod prelude {
-    pub use crate::package::{Config, GHPackage, Mode, SimplePackage};
-    pub use crate::traits::{Entry, EntryKey, EntryValue};
-    pub use crate::val::{GHValue, SimpleValue};
+    pub use crate::auth::{
+        Credential, CredentialProvider, EnvProvider, NetrcProvider, StaticProvider,
+    };
+    pub use crate::builder::FetcherBuilder;
+    pub use crate::cache::{Quota, Stats};
+    pub use crate::compress::Compression;
+    pub use crate::crypt::{EncryptionMethod, EnvKey, KeyProvider, StaticKey};
+    pub use crate::filter::Filter;
+    pub use crate::history::Version;
+    pub use crate::layer::Provenance;
+    pub use crate::lint::{Diagnostic, Lint, Severity};
+    pub use crate::lock::{LockedEntry, Lockfile};
+    pub use crate::meta::Record;
+    pub use crate::mirror::MirrorRule;
+    pub use crate::package::{Config, GHPackage, GitPackage, Mode, SimplePackage, Versioned};
+    pub use crate::plan::{Change, Plan};
+    pub use crate::report::{Commit, FetchReport, Outcome};
+    pub use crate::rewrite::RewriteRule;
+    pub use crate::s3::{S3Config, S3Credentials};
+    pub use crate::traits::{Entry, EntryKey, EntryValue, RequestSpec};
+    pub use crate::val::{GHValue, GitValue, SimpleValue};
+    pub use crate::verify::{Issue, Repair, VerifyReport};
     pub use crate::Fetcher;
 }
 
@@ -49,84 +138,126 @@ pub fn cache_plus<P: AsRef<Path>>(sub_dir: P) -> PathBuf {
     dirs::cache_dir().unwrap().join(sub_dir)
 }
 
+/// Returns the SHA-256 of the bytes as a lowercase hex string
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
 /// `ResponseMethod` enum to specify the method of fetching the response
 ///
 /// - `Bytes`: Fetch the full response using the `bytes` method
 /// - `Chunk`: Fetch the response in chunks using the `chunk` method
 /// - `BytesStream`: Fetch the response in a stream of bytes using the `bytes_stream` method
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ResponseMethod {
+    #[default]
     Bytes,
     Chunk,
     BytesStream,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum NotifyMethod {
+    #[default]
     Log,
     ProgressBar,
     Silent,
 }
 
-impl Default for NotifyMethod {
-    fn default() -> Self {
-        Self::Log
-    }
-}
-
 /// `FetchMethod` enum to specify the method of fetching the response
 ///
 /// - `Async`: Fetch the response asynchronously using `tokio::spawn`
 /// - `Channel`: Fetch the response using a bounded multi-producer single-consumer channel
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone)]
 pub enum FetchMethod {
+    #[default]
     Async,
     Watch,
     #[cfg(feature = "unstable")]
     Sync,
 }
 
-impl Default for FetchMethod {
-    fn default() -> Self {
-        Self::Async
-    }
-}
-
-impl Default for ResponseMethod {
-    fn default() -> Self {
-        Self::Bytes
-    }
+/// `FetchMode` enum to specify how the lockfile is used
+///
+/// - `Update`: Fetch as usual and record what each entry resolved to in the lockfile
+/// - `Locked`: Refuse to fetch anything whose version, url or content differs from
+///   the lockfile, which is left untouched
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum FetchMode {
+    #[default]
+    Update,
+    Locked,
 }
 
 /// Fetcher struct that will be used to fetch and cache data
 ///
 /// - `entries`: List of entries to fetch
 /// - `db`: sled db to cache the fetched data
+/// - `lock_path`: Path to the lockfile written after fetching
 /// - `client`: reqwest client to fetch the data
 /// - `response_method`: Method of fetching the response
 /// - `encryption_method`: Method of encrypting and decrypting the response
+/// - `key_provider`: Provides the key the response is encrypted with
 #[derive(Debug, Clone)]
 pub struct Fetcher<E: Entry> {
     /// List of entries to fetch
     entries: Arc<Vec<E>>,
-    /// Path to the config file
-    config_path: PathBuf,
+    /// Path to the config file (`None` if the config was given in memory)
+    config_path: Option<PathBuf>,
     /// Config struct to hold the configuration
     config: Config<E>,
-    /// Type of config file (json or toml)
+    /// Type of config file (json, toml, yaml or ron)
     config_type: Mode,
     /// sled db to cache the fetched data
     db: Db,
     /// Path to the db file
     db_path: PathBuf,
+    /// Records of the cached responses, by key
+    meta: Tree,
+    /// Previous values and their records, by key and id
+    history: Tree,
+    /// Number of previous versions kept for each key
+    keep_versions: usize,
+    /// Store the fetched values all at once, or none of them
+    transactional: bool,
+    /// How cached values are compressed at rest
+    compression: Compression,
+    /// How cached values are encrypted at rest
+    encryption_method: EncryptionMethod,
+    /// Provides the key cached values are encrypted with
+    key_provider: Option<Arc<dyn KeyProvider>>,
+    /// Path to the lockfile (`None` to not use one)
+    lock_path: Option<PathBuf>,
+    /// Whether fetching updates or follows the lockfile
+    fetch_mode: FetchMode,
+    /// Prune the keys that aren't entries after fetching
+    auto_prune: bool,
+    /// Limits on the size of the cache
+    quota: Quota,
+    /// How long cached values stay fresh, unless the entry has its own TTL
+    ttl: Option<Duration>,
     /// reqwest client to fetch the data
     client: Client,
+    /// Whether the client was set with `set_client`, which may follow redirects itself
+    custom_client: bool,
     /// Method of fetching the response
     response_method: ResponseMethod,
     /// Method of notifying the user
     notify_method: NotifyMethod,
     /// Multi progress bar to show multiple progress bars
     multi_pb: Arc<MultiProgress>,
+    /// Settings used to resolve and sign `s3://` urls
+    s3: S3Config,
+    /// Global mirror rules applied to every url before fetching
+    mirror_rules: Arc<Vec<MirrorRule>>,
+    /// Url rewrite rules applied before the ones from the config
+    rewrite_rules: Arc<Vec<RewriteRule>>,
+    /// Providers of per-host credentials, consulted before the config's credentials
+    credential_providers: Arc<Vec<Arc<dyn CredentialProvider>>>,
+    /// Maximum number of redirects to follow
+    max_redirects: usize,
+    /// Limits the number of entries fetched at the same time
+    concurrency: Option<Arc<Semaphore>>,
 }
 
 // Constructor and Setup Methods
@@ -137,23 +268,34 @@ impl<E: Entry + Clone + Send + Sync + 'static + for<'de> Deserialize<'de>> Fetch
         config_type: Mode,
         db_path: P,
     ) -> Result<Self> {
-        let client = Client::builder().zstd(true).build()?;
-
-        let config = Config::from_file(&config_path, config_type).await?;
-        let entries = config.packages_owned();
-
-        Ok(Self {
-            entries: Arc::new(entries),
-            db: sled::open(&db_path)?,
-            db_path: PathBuf::from(db_path.as_ref()),
-            config,
-            config_path: config_path.as_ref().to_path_buf(),
-            config_type,
-            client,
-            response_method: ResponseMethod::default(),
-            notify_method: NotifyMethod::Log,
-            multi_pb: Arc::new(MultiProgress::new()),
-        })
+        FetcherBuilder::new(config_path, config_type, db_path)
+            .build()
+            .await
+    }
+
+    /// Create a new `Fetcher` instance with a config path and db path,
+    /// detecting the format of the config from its extension or contents
+    pub async fn open<P: AsRef<Path> + Send + Sync>(config_path: P, db_path: P) -> Result<Self> {
+        FetcherBuilder::open(config_path, db_path).build().await
+    }
+
+    /// Create a new `Fetcher` instance from an in-memory config and db path
+    pub fn from_config<P: AsRef<Path>>(config: Config<E>, db_path: P) -> Result<Self> {
+        FetcherBuilder::in_memory(db_path).build_with_config(config)
+    }
+
+    /// Create a new `Fetcher` instance from a list of entries and db path
+    pub fn from_entries<P: AsRef<Path>>(entries: Vec<E>, db_path: P) -> Result<Self> {
+        FetcherBuilder::in_memory(db_path).build_with_entries(entries)
+    }
+
+    /// Create a `FetcherBuilder` to configure the HTTP client and fetching settings
+    pub fn builder<P: AsRef<Path>>(
+        config_path: P,
+        config_type: Mode,
+        db_path: P,
+    ) -> FetcherBuilder {
+        FetcherBuilder::new(config_path, config_type, db_path)
     }
 
     #[cfg(feature = "unstable")]
@@ -166,12 +308,74 @@ impl<E: Entry + Clone + Send + Sync + 'static + for<'de> Deserialize<'de>> Fetch
         futures::executor::block_on(Self::new(config_path, config_type, db_path))
     }
 
+    /// Returns the entries that will be fetched
+    pub fn entries(&self) -> &[E] {
+        &self.entries
+    }
+
+    /// Returns the config the entries were loaded from
+    pub fn config(&self) -> &Config<E> {
+        &self.config
+    }
+
+    /// Replace all entries
+    ///
+    /// When watching a config file, the entries are replaced by the file's packages on every change
+    pub fn set_entries(&mut self, entries: Vec<E>) {
+        self.entries = Arc::new(entries);
+    }
+
+    /// Add an entry, failing if an entry with the same key exists
+    pub fn add_entry(&mut self, entry: E) -> Result<()> {
+        let key = entry.key();
+        if self.position(&key).is_some() {
+            bail!("An entry with the key {} already exists", key);
+        }
+        Arc::make_mut(&mut self.entries).push(entry);
+        Ok(())
+    }
+
+    /// Remove the entry with the key, returning it if it existed
+    ///
+    /// The cached value is kept in the db
+    pub fn remove_entry(&mut self, key: &E::Key) -> Option<E> {
+        let index = self.position(key)?;
+        Some(Arc::make_mut(&mut self.entries).remove(index))
+    }
+
+    /// Replace the entry with the same key (or add it), returning the previous entry
+    pub fn replace_entry(&mut self, entry: E) -> Option<E> {
+        let entries = Arc::make_mut(&mut self.entries);
+        match entries
+            .iter()
+            .position(|x| x.key().bytes() == entry.key().bytes())
+        {
+            Some(index) => Some(std::mem::replace(&mut entries[index], entry)),
+            None => {
+                entries.push(entry);
+                None
+            }
+        }
+    }
+
+    fn position(&self, key: &E::Key) -> Option<usize> {
+        let key = key.bytes();
+        self.entries.iter().position(|x| x.key().bytes() == key)
+    }
+
     /// Set the client to be used for fetching the data
     ///
     /// This is useful when you want to use a custom client with custom settings
     /// when using `Client::builder()`
+    ///
+    /// Redirects are followed by the `Fetcher` so that credentials are never forwarded to
+    /// another origin, but a custom client may follow them itself. reqwest drops the
+    /// `Authorization` header on those, so `Basic` and `Bearer` credentials are still sent,
+    /// while fetching a url with a `Header` credential fails. Use `FetcherBuilder` to configure
+    /// the client instead when `Header` credentials are needed
     pub fn set_client(&mut self, client: Client) {
         self.client = client;
+        self.custom_client = true;
     }
 
     /// Set the response method to be used for fetching the response
@@ -188,8 +392,150 @@ impl<E: Entry + Clone + Send + Sync + 'static + for<'de> Deserialize<'de>> Fetch
         self.response_method = response_method;
     }
 
+    /// Set the settings used to resolve and sign `s3://bucket/key` urls
+    ///
+    /// By default the region and credentials are read from the environment
+    /// and virtual-hosted addressing against AWS is used
+    pub fn set_s3_config(&mut self, s3: S3Config) {
+        self.s3 = s3;
+    }
+
+    /// Set the global mirror rules
+    ///
+    /// For every url (primary or an entry's mirror) the urls produced by the rules are tried
+    /// before the url itself, and the next candidate is tried if a fetch fails or the
+    /// response doesn't match the entry's checksum
+    pub fn set_mirror_rules(&mut self, rules: Vec<MirrorRule>) {
+        self.mirror_rules = Arc::new(rules);
+    }
+
+    /// Add a global mirror rule, see `set_mirror_rules`
+    pub fn add_mirror_rule(&mut self, rule: MirrorRule) {
+        Arc::make_mut(&mut self.mirror_rules).push(rule);
+    }
+
+    /// Set the url rewrite rules
+    ///
+    /// Before a url is requested it is rewritten by the first matching rule, checking these
+    /// rules before the `rewrites` from the config file. The cache key and `is_same` still
+    /// use the original url
+    pub fn set_rewrite_rules(&mut self, rules: Vec<RewriteRule>) {
+        self.rewrite_rules = Arc::new(rules);
+    }
+
+    /// Add a url rewrite rule, see `set_rewrite_rules`
+    pub fn add_rewrite_rule(&mut self, rule: RewriteRule) {
+        Arc::make_mut(&mut self.rewrite_rules).push(rule);
+    }
+
+    /// Add a provider of per-host credentials
+    ///
+    /// Providers are consulted in the order they were added, before the `[credentials]`
+    /// table of the config file
+    pub fn add_credential_provider<C: CredentialProvider + 'static>(&mut self, provider: C) {
+        Arc::make_mut(&mut self.credential_providers).push(Arc::new(provider));
+    }
+
+    /// Set the path of the lockfile, or `None` to not write or read one
+    ///
+    /// By default there's none, `FetcherBuilder::lockfile` enables `quickfetch.lock` next to
+    /// the config file
+    pub fn set_lock_path<P: AsRef<Path>>(&mut self, lock_path: Option<P>) {
+        self.lock_path = lock_path.map(|x| x.as_ref().to_path_buf());
+    }
+
+    /// Returns the path of the lockfile
+    pub fn lock_path(&self) -> Option<&Path> {
+        self.lock_path.as_deref()
+    }
+
+    /// Set the fetch mode, by default `FetchMode::Update`
+    ///
+    /// - `Update`: Fetch as usual and record what each entry resolved to in the lockfile
+    /// - `Locked`: Refuse to fetch anything whose version, url or content differs
+    ///   from the lockfile
+    pub fn set_fetch_mode(&mut self, fetch_mode: FetchMode) {
+        self.fetch_mode = fetch_mode;
+    }
+
+    /// Prune the cached keys that aren't entries anymore after every fetch, including the
+    /// fetches of watch mode (disabled by default), see `prune`
+    pub fn set_auto_prune(&mut self, auto_prune: bool) {
+        self.auto_prune = auto_prune;
+    }
+
+    /// Set the limits on the size of the cache (by default there are none)
+    ///
+    /// The least recently used values are evicted after every fetch and by `gc`
+    /// until the cache is within the quota
+    pub fn set_quota(&mut self, quota: Quota) {
+        self.quota = quota;
+    }
+
+    /// Set how long cached values stay fresh (by default forever)
+    ///
+    /// Once expired, a value is fetched again even if `is_same` holds. Entries can override
+    /// it with `Entry::ttl`
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+    }
+
+    /// Set the number of previous versions kept for each key (by default none)
+    ///
+    /// When a fetch replaces a cached value, the old value is kept as history to roll back
+    /// to or write, see `versions`
+    pub fn set_keep_versions(&mut self, keep_versions: usize) {
+        self.keep_versions = keep_versions;
+    }
+
+    /// Set how cached values are compressed at rest (by default they aren't)
+    ///
+    /// Only values stored afterwards are affected, and values are read back the same however
+    /// they were stored
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Set how cached values are encrypted at rest (by default they aren't), which requires a
+    /// key provider
+    ///
+    /// Only values stored afterwards are encrypted, values stored before stay readable
+    pub fn set_encryption_method(&mut self, encryption_method: EncryptionMethod) {
+        self.encryption_method = encryption_method;
+    }
+
+    /// Set the provider of the key cached values are encrypted with
+    ///
+    /// The provider is also needed to read values that were encrypted, even once the
+    /// encryption method is set back to `EncryptionMethod::None`
+    pub fn set_key_provider<P: KeyProvider + 'static>(&mut self, provider: P) {
+        self.key_provider = Some(Arc::new(provider));
+    }
+
+    /// Store the fetched values of an async fetch all at once, or none of them if any entry
+    /// fails (disabled by default)
+    ///
+    /// Every value is fetched to memory first and then all of them are stored in a single
+    /// transaction, so entries that must share a version are never left half updated
+    pub fn set_transactional(&mut self, transactional: bool) {
+        self.transactional = transactional;
+    }
+
+    /// Set the maximum number of redirects to follow (by default 10)
+    pub fn set_max_redirects(&mut self, max_redirects: usize) {
+        self.max_redirects = max_redirects;
+    }
+
+    /// Limit the number of entries fetched at the same time (by default there's no limit)
+    pub fn set_concurrency(&mut self, concurrency: Option<usize>) {
+        self.concurrency = concurrency.map(|n| Arc::new(Semaphore::new(n.max(1))));
+    }
+
     /// Set the notify method to be used for notifying the user
     /// By default `self.notify_method = NotifyMethod::Log`
+    ///
+    /// Panics if `ProgressBar` is used with `ResponseMethod::Bytes`, use `FetcherBuilder`
+    /// to get an error instead
     pub fn set_notify_method(&mut self, notify_method: NotifyMethod) {
         self.notify_method = notify_method;
         if notify_method == NotifyMethod::ProgressBar {
@@ -228,24 +574,122 @@ impl<E: Entry + Clone + Send + Sync + 'static> Fetcher<E> {
     /// Export the db to a vector of key value pairs and an iterator of values
     ///
     /// > Useful when needing to migrate the db from an older version to a newer version
+    #[allow(clippy::type_complexity)]
     pub fn export(&self) -> Vec<(Vec<u8>, Vec<u8>, impl Iterator<Item = Vec<Vec<u8>>> + Sized)> {
         self.db.export()
     }
 
     /// Import the db from a vector of key value pairs and an iterator of values
     ///
+    /// Values exported before stored values had a header are given one, and exports of a
+    /// newer format are rejected
+    ///
     /// > Useful when needing to migrate the db from an older version to a newer version
     pub fn import(
         &self,
         export: Vec<(Vec<u8>, Vec<u8>, impl Iterator<Item = Vec<Vec<u8>>> + Sized)>,
-    ) {
-        self.db.import(export)
+    ) -> Result<()> {
+        stored::import(&self.db, export)
     }
 
     pub fn clear(&self) -> Result<()> {
         self.db.clear()?;
+        self.meta.clear()?;
+        self.history.clear()?;
         Ok(())
     }
+
+    /// Removes the cached value of the key, its record and its history
+    fn forget(&self, key: &[u8]) -> Result<()> {
+        self.db.remove(key)?;
+        self.meta.remove(key)?;
+        for item in self.history.scan_prefix(history::prefix(key)).keys() {
+            self.history.remove(item?)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the key provider, failing if there's none
+    fn key_provider(&self) -> Result<&dyn KeyProvider> {
+        match &self.key_provider {
+            Some(provider) => Ok(provider.as_ref()),
+            None => bail!("Encrypted values require a key provider"),
+        }
+    }
+
+    /// Returns the value of the key as it's stored, compressed then encrypted behind its header
+    ///
+    /// `compress` is the entry's preference if it has one
+    fn encode(&self, key: &[u8], value: Vec<u8>, compress: Option<bool>) -> Result<Vec<u8>> {
+        let (value, compressed) = compress::encode(self.compression, compress, value)?;
+        let header = Header {
+            compressed,
+            encrypted: self.encryption_method != EncryptionMethod::None,
+        };
+        let value = match self.encryption_method {
+            EncryptionMethod::None => value,
+            method => crypt::encrypt(method, self.key_provider()?, &header.aad(key), &value)?,
+        };
+        Ok(header.prepend(&value))
+    }
+
+    /// Returns the header of a stored value of the key and the value decrypted, but still
+    /// compressed if it was
+    fn decrypt(&self, key: &[u8], stored: IVec) -> Result<(Header, IVec)> {
+        let (header, value) = stored::split(stored)?;
+        if !header.encrypted {
+            return Ok((header, value));
+        }
+        let value = crypt::decrypt(self.key_provider()?, &header.aad(key), &value)
+            .with_context(|| format!("Failed to read {}", E::Key::from_ivec(key.into())))?;
+        Ok((header, value.into()))
+    }
+
+    /// Returns a stored value of the key as it was before it was stored
+    fn decode(&self, key: &[u8], stored: IVec) -> Result<IVec> {
+        let (header, value) = self.decrypt(key, stored)?;
+        match header.compressed {
+            true => Ok(compress::decode(&value)?.into()),
+            false => Ok(value),
+        }
+    }
+
+    /// Returns the cached value of the key as it was before it was stored
+    fn cached(&self, key: &[u8]) -> Result<Option<IVec>> {
+        self.db.get(key)?.map(|x| self.decode(key, x)).transpose()
+    }
+
+    /// Returns the keys in the db that aren't any entry
+    fn orphaned(&self) -> Result<Vec<IVec>> {
+        let keys = self
+            .entries
+            .iter()
+            .map(|x| x.key().bytes())
+            .collect::<HashSet<_>>();
+        let mut orphaned = Vec::new();
+        for item in self.db.iter() {
+            let (key, _) = item?;
+            if !keys.contains(key.as_ref()) {
+                orphaned.push(key);
+            }
+        }
+        Ok(orphaned)
+    }
+
+    /// Removes the cached values, records and history of the keys that aren't entries anymore,
+    /// returning the removed keys
+    ///
+    /// With `dry_run` nothing is removed and the keys that would be removed are returned
+    pub fn prune(&self, dry_run: bool) -> Result<Vec<String>> {
+        let mut pruned = Vec::new();
+        for key in self.orphaned()? {
+            if !dry_run {
+                self.forget(&key)?;
+            }
+            pruned.push(E::Key::from_ivec(key).to_string());
+        }
+        Ok(pruned)
+    }
 }
 
 // Handles and Fetching Entries
@@ -319,7 +763,6 @@ impl<E: Entry + Clone + Send + Sync + 'static + for<'de> Deserialize<'de>> Fetch
     ///
     /// The fetching method is `Async` and the notification method is `log`
     pub async fn watching(&mut self) {
-        info!("Watching {}", &self.config_path.display());
         if let Err(e) = self.watch().await {
             error!("Error: {:?}", e)
         }
@@ -344,7 +787,11 @@ impl<E: Entry + Clone + Send + Sync + 'static + for<'de> Deserialize<'de>> Fetch
     async fn watch(&mut self) -> notify::Result<()> {
         self.notify_method = NotifyMethod::Log;
         let (mut watcher, mut rx) = Self::watcher().await?;
-        watcher.watch(&self.config_path, RecursiveMode::Recursive)?;
+        let Some(config_path) = self.config_path.clone() else {
+            return Err(notify::Error::generic("No config file to watch"));
+        };
+        info!("Watching {}", config_path.display());
+        watcher.watch(&config_path, RecursiveMode::Recursive)?;
 
         while let Some(res) = rx.recv().await {
             match res {
@@ -359,30 +806,283 @@ impl<E: Entry + Clone + Send + Sync + 'static + for<'de> Deserialize<'de>> Fetch
         info!("Event: {:?}", event.kind);
         match event.kind {
             EventKind::Modify(_) => {
-                self.config = Config::from_file(&self.config_path, self.config_type).await?;
+                let Some(config_path) = &self.config_path else {
+                    return Ok(());
+                };
+                self.config = Config::from_file(config_path, self.config_type).await?;
+                self.entries = Arc::new(self.config.packages_owned());
                 self.async_fetch().await?;
             }
             EventKind::Remove(_) => {
-                info!("Removed {}", &self.config_path.display());
+                info!("Removed config file");
                 info!("Clearing DB");
-                self.db.clear().unwrap();
+                self.clear().unwrap();
             }
             _ => debug!("Other event type"),
         }
         Ok(())
     }
 
-    async fn handle_entry(&self, entry: E) -> Result<()> {
+    /// Builds a request to the url, resolving and signing `s3://` urls
+    ///
+    /// The query, body and timeout of the spec are always applied. If `authorize` is set,
+    /// the spec's headers and the credential for the url's host are attached
+    fn request(
+        &self,
+        method: Method,
+        url: &str,
+        spec: &RequestSpec,
+        authorize: bool,
+    ) -> Result<RequestBuilder> {
+        let mut request = if s3::is_s3_url(url) {
+            let mut object_url = self.s3.object_url(url)?;
+            if !spec.query.is_empty() {
+                object_url.query_pairs_mut().extend_pairs(&spec.query);
+            }
+            let headers = self.s3.sign(&method, &object_url, chrono::Utc::now())?;
+            self.client.request(method, object_url).headers(headers)
+        } else {
+            let mut url = Url::parse(url)?;
+            if !spec.query.is_empty() {
+                url.query_pairs_mut().extend_pairs(&spec.query);
+            }
+            let credential = url.host_str().filter(|_| authorize).and_then(|host| {
+                self.credential_providers
+                    .iter()
+                    .find_map(|provider| provider.credential(host))
+                    .or_else(|| self.config.credentials().credential(host))
+            });
+            if let Some(Credential::Header { name, .. }) = &credential {
+                if self.custom_client {
+                    bail!(
+                        "Not sending the {} credential for {}, the client set with `set_client` may forward it on redirects",
+                        name,
+                        url.host_str().unwrap_or_default()
+                    );
+                }
+            }
+            let request = self.client.request(method, url);
+            match credential {
+                Some(credential) => credential.apply(request),
+                None => request,
+            }
+        };
+
+        if authorize {
+            for (name, value) in &spec.headers {
+                request = request.header(name, value);
+            }
+        }
+        if let Some(body) = &spec.body {
+            request = request.body(body.clone());
+        }
+        if let Some(timeout) = spec.timeout {
+            request = request.timeout(timeout);
+        }
+        Ok(request)
+    }
+
+    /// Sends a request, following redirects so credentials are dropped once they leave the origin
+    async fn send(&self, method: Method, url: &str, spec: &RequestSpec) -> Result<Response> {
+        let mut response = self
+            .request(method.clone(), url, spec, true)?
+            .send()
+            .await?;
+        let origin = response.url().origin();
+        let mut method = method;
+        let mut spec = spec.clone();
+        let mut authorize = true;
+        let mut redirects = 0;
+
+        while response.status().is_redirection() {
+            // A redirection without a location has no response to cache
+            let Some(location) = response.headers().get(LOCATION) else {
+                bail!(
+                    "{} responded with {} without a location",
+                    response.url(),
+                    response.status()
+                );
+            };
+            if redirects == self.max_redirects {
+                bail!("Too many redirects fetching {}", url);
+            }
+            let next = response.url().join(location.to_str()?)?;
+            authorize = authorize && next.origin() == origin;
+            if !authorize {
+                debug!("Dropping credentials on redirect to {}", next);
+            }
+
+            // The location already has its query, and like browsers a 303 (or a 301/302 after
+            // anything but GET or HEAD) continues with a GET without the body
+            spec.query.clear();
+            let status = response.status();
+            if method != Method::HEAD
+                && (status == StatusCode::SEE_OTHER
+                    || (method != Method::GET
+                        && (status == StatusCode::MOVED_PERMANENTLY
+                            || status == StatusCode::FOUND)))
+            {
+                method = Method::GET;
+                spec.body = None;
+            }
+
+            response = self
+                .request(method.clone(), next.as_str(), &spec, authorize)?
+                .send()
+                .await?;
+            redirects += 1;
+        }
+
+        Ok(response.error_for_status()?)
+    }
+
+    /// Rewrites a url with the fetcher's rules followed by the config's rules
+    fn rewrite(&self, url: &str) -> String {
+        rewrite::rewrite(self.rewrite_rules.iter().chain(self.config.rewrites()), url)
+    }
+
+    /// Checks if the ETag of a cached S3 object no longer matches the one in its record
+    ///
+    /// Values served by a mirror are not compared, since the mirror's ETag isn't the object's,
+    /// and a failed HEAD request keeps the cached value so cached objects work offline
+    async fn revision_changed(&self, url: &str, key: &[u8], spec: &RequestSpec) -> Result<bool> {
+        if !s3::is_s3_url(url) {
+            return Ok(false);
+        }
+        let object = self.rewrite(url);
+        let Some(cached) = self
+            .record_of(key)?
+            .filter(|x| x.url == object)
+            .and_then(|x| x.etag)
+        else {
+            return Ok(false);
+        };
+        let spec = scoped_spec(spec, url, &object);
+        match self.send(Method::HEAD, &object, &spec).await {
+            Ok(response) => {
+                let etag = response.headers().get(ETAG).and_then(|x| x.to_str().ok());
+                Ok(etag.is_some_and(|etag| etag != cached))
+            }
+            Err(e) => {
+                warn!("Failed to check the revision of {}: {}", url, e);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Fetches the response from a single url, returning it with the ETag if one was sent
+    /// and the url that served it after redirects
+    async fn fetch_from(
+        &self,
+        url: &str,
+        key: &E::Key,
+        commit: Option<&str>,
+        spec: &RequestSpec,
+    ) -> Result<(Bytes, Option<String>, String)> {
+        if git::is_git_url(url) {
+            let commit = commit.ok_or_else(|| anyhow!("{} was not resolved to a commit", url))?;
+            let snapshot = git::snapshot(url, commit).await?;
+            return Ok((Bytes::from(snapshot), None, url.to_string()));
+        }
+
+        let method = match &spec.method {
+            Some(method) => Method::from_bytes(method.to_uppercase().as_bytes())?,
+            None => Method::GET,
+        };
+        let response = self.send(method, url, spec).await?;
+        let final_url = response.url().to_string();
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|x| x.to_str().ok())
+            .map(String::from);
+        let bytes = self.resp_bytes(response, key.to_string()).await?;
+        Ok((bytes, etag, final_url))
+    }
+
+    /// Fetches an entry unless its cached value is up to date, returning the value to store
+    ///
+    /// With a locked entry, the entry must still have the locked url (git entries are fetched at
+    /// the locked commit) and the response must match the locked checksum. Mirrors and rewrites
+    /// still apply, a candidate serving another response is failed over like a checksum
+    /// mismatch, and a cached response that doesn't match is fetched again
+    async fn stage(&self, entry: E, locked: Option<LockedEntry>) -> Result<Option<Staged>> {
         let key = entry.key();
         let mut value = entry.value();
         let key_bytes = key.bytes();
+        let url = value.url();
+        let spec = entry.request();
+
+        // Git refs are resolved to a commit up front so `is_same` can compare commits
+        let commit = match (git::is_git_url(&url), &locked) {
+            (false, _) => None,
+            (true, Some(locked)) => git::pinned_commit(&locked.url),
+            (true, None) => Some(git::resolve(&self.rewrite(&url)).await?),
+        };
+        if let Some(commit) = &commit {
+            value.set_revision(commit);
+        }
+
+        let urls = std::iter::once(url.clone()).chain(entry.mirrors());
+        let candidates = mirror::candidates(&self.mirror_rules, urls)
+            .iter()
+            .map(|x| self.rewrite(x))
+            .collect::<Vec<_>>();
+        if let Some(locked) = &locked {
+            let version = entry.version();
+            if locked.version != version {
+                bail!(
+                    "{} has version {}, but {} locks {}",
+                    key,
+                    version.as_deref().unwrap_or("none"),
+                    LOCKFILE,
+                    locked.version.as_deref().unwrap_or("none")
+                );
+            }
+            let resolved = meta::resolved_url(&url, commit.clone());
+            if resolved != locked.url {
+                bail!(
+                    "{} resolves to {}, but {} locks {}",
+                    key,
+                    resolved,
+                    LOCKFILE,
+                    locked.url
+                );
+            }
+        }
+        let checksums = entry
+            .checksum()
+            .into_iter()
+            .chain(locked.as_ref().map(|x| x.sha256.clone()))
+            .collect::<Vec<_>>();
 
         // Check if the entry exists and if it needs updating
-        let should_update = match self.db.get(&key_bytes)? {
-            Some(curr_val) => {
-                let cv = E::Value::from_ivec(curr_val.clone());
-                !value.is_same(&cv)
+        let should_update = match self.cached(&key_bytes)? {
+            Some(curr_val) if self.record_of(&key_bytes)?.is_some_and(|x| x.held) => {
+                let cv = E::Value::try_from_ivec(curr_val)
+                    .with_context(|| format!("Failed to read the held value of {}", key))?;
+                if let Some(locked) = &locked {
+                    if sha256_hex(&cv.response()) != locked.sha256 {
+                        bail!("{} is held at a value that doesn't match {}", key, LOCKFILE);
+                    }
+                }
+                false
             }
+            // A cached value that can't be read is fetched again
+            Some(curr_val) => match E::Value::try_from_ivec(curr_val) {
+                Ok(cv) => {
+                    !value.is_same(&cv)
+                        || self.is_expired(&entry)?
+                        || self.revision_changed(&url, &key_bytes, &spec).await?
+                        || locked
+                            .as_ref()
+                            .is_some_and(|x| sha256_hex(&cv.response()) != x.sha256)
+                }
+                Err(e) => {
+                    warn!("{} has a cached value that can't be read: {:#}", key, e);
+                    true
+                }
+            },
             None => true,
         };
 
@@ -391,59 +1091,432 @@ impl<E: Entry + Clone + Send + Sync + 'static + for<'de> Deserialize<'de>> Fetch
                 key.log_caching();
             }
 
-            // Fetch new data
-            let response = self.client.get(value.url()).send().await?;
-            let bytes = self.resp_bytes(response, key.to_string()).await?;
+            // Fetch new data, failing over to the next mirror on errors or checksum mismatches
+            let mut errors = Vec::new();
+            let mut fetched = None;
+            for candidate in candidates {
+                match self
+                    .fetch_from(
+                        &candidate,
+                        &key,
+                        commit.as_deref(),
+                        &scoped_spec(&spec, &url, &candidate),
+                    )
+                    .await
+                {
+                    Ok((bytes, etag, final_url)) => {
+                        let sha256 = sha256_hex(&bytes);
+                        if checksums.iter().all(|x| sha256.eq_ignore_ascii_case(x)) {
+                            fetched = Some((candidate, final_url, bytes, etag));
+                            break;
+                        }
+                        warn!("{} checksum mismatch from {}", key, candidate);
+                        errors.push(format!("{}: checksum mismatch", candidate));
+                    }
+                    Err(e) => {
+                        warn!("{} failed from {}: {}", key, candidate, e);
+                        errors.push(format!("{}: {}", candidate, e));
+                    }
+                }
+            }
+            let (served_by, final_url, bytes, etag) = fetched
+                .ok_or_else(|| anyhow!("Failed to fetch {} ({})", key, errors.join(", ")))?;
+            let mut record = Record::new(
+                meta::resolved_url(&served_by, commit),
+                final_url,
+                entry.version(),
+                &bytes,
+            );
+            let now = SystemTime::now();
+            record.id = self.next_id()?;
+            record.fetched_at = Some(now);
+            record.accessed_at = Some(now);
+            record.etag = etag;
+            record.pinned = self.record_of(&key_bytes)?.is_some_and(|x| x.pinned);
             value.set_response(&bytes);
 
-            // Update the database
-            if let Some(curr_val) = self.db.get(&key_bytes)? {
-                let _ = self
-                    .db
-                    .compare_and_swap(key_bytes, Some(curr_val), Some(value.bytes()))?;
-            } else {
-                self.db.insert(key_bytes, value.bytes())?;
+            Ok(Some(Staged {
+                value: self.encode(&key_bytes, value.bytes(), entry.compress())?,
+                key: key_bytes,
+                record,
+                restored: None,
+            }))
+        } else {
+            if self.notify_method == NotifyMethod::Log {
+                key.log_cache();
             }
-        } else if self.notify_method == NotifyMethod::Log {
-            key.log_cache();
+            Ok(None)
         }
+    }
 
-        Ok(())
+    /// Stores fetched values, moving the values they replace into history, in a single
+    /// transaction so either all of them are stored or none are
+    fn commit(&self, staged: &[Staged]) -> Result<()> {
+        let mut values = Batch::default();
+        let mut records = Batch::default();
+        let mut history = Batch::default();
+        for staged in staged {
+            let restored = staged.restored.as_deref();
+            if let Some(current) = self.db.get(&staged.key)? {
+                self.keep_history(&staged.key, &current, restored, &mut history)?;
+            }
+            if let Some(restored) = restored {
+                history.remove(restored);
+            }
+            values.insert(staged.key.as_slice(), staged.value.as_slice());
+            records.insert(staged.key.as_slice(), staged.record.to_bytes());
+        }
+
+        let db: &Tree = &self.db;
+        (db, &self.meta, &self.history)
+            .transaction(|(db, meta, history_tree)| {
+                db.apply_batch(&values)?;
+                meta.apply_batch(&records)?;
+                history_tree.apply_batch(&history)?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError| {
+                anyhow!(
+                    "Failed to store the fetched values, nothing was stored: {:?}",
+                    e
+                )
+            })
     }
 
     #[cfg(feature = "unstable")]
-    fn handle_entry_sync(&self, entry: E) -> Result<()> {
-        futures::executor::block_on(self.handle_entry(entry))?;
+    fn handle_entry_sync(&self, entry: E, locked: Option<LockedEntry>) -> Result<()> {
+        if let Some(staged) = futures::executor::block_on(self.stage(entry, locked))? {
+            self.commit(&[staged])?;
+        }
         Ok(())
     }
 
+    /// Returns the entries selected by the filter
+    pub fn select(&self, filter: &Filter<E>) -> Vec<E> {
+        self.entries
+            .iter()
+            .filter(|x| filter.matches(x))
+            .cloned()
+            .collect()
+    }
+
     /// Fetches and stores all results to the db
     pub async fn async_fetch(&mut self) -> Result<()> {
+        self.async_fetch_filtered(&Filter::All).await
+    }
+
+    /// Returns the entries selected by the filter and all of their dependencies,
+    /// with dependencies before the entries depending on them
+    ///
+    /// Fails if a dependency isn't an entry or the dependencies form a cycle
+    pub fn resolve(&self, filter: &Filter<E>) -> Result<Vec<E>> {
+        let graph = deps::check(&self.entries)?;
+        let roots = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| filter.matches(x))
+            .map(|(index, _)| index);
+        Ok(deps::closure(&graph, roots)
+            .into_iter()
+            .map(|index| self.entries[index].clone())
+            .collect())
+    }
+
+    /// Returns what `async_fetch` would do, without any network access or writes to the db
+    ///
+    /// Entries are compared to their cached values with `is_same` like when fetching, S3 ETags
+    /// aren't checked and git entries are only compared when they're pinned to a commit
+    pub fn plan(&self) -> Result<Plan> {
+        self.plan_filtered(&Filter::All)
+    }
+
+    /// Returns what `async_fetch_filtered` would do, see `plan`
+    ///
+    /// Orphaned keys are the keys in the db that aren't any entry, not only the selected ones
+    pub fn plan_filtered(&self, filter: &Filter<E>) -> Result<Plan> {
+        let mut plan = Plan::default();
+        for entry in self.resolve(filter)? {
+            let key = entry.key();
+            let mut value = entry.value();
+            let url = value.url();
+            let Some(cached) = self.cached(&key.bytes())? else {
+                plan.new.push(Change {
+                    key: key.to_string(),
+                    from: None,
+                    to: entry.version(),
+                });
+                continue;
+            };
+            if self.record_of(&key.bytes())?.is_some_and(|x| x.held) {
+                plan.cached.push(key.to_string());
+                continue;
+            }
+            if git::is_git_url(&url) {
+                match git::pinned_commit(&self.rewrite(&url)) {
+                    Some(commit) => value.set_revision(&commit),
+                    None => {
+                        plan.unresolved.push(key.to_string());
+                        continue;
+                    }
+                }
+            }
+            let is_same = E::Value::try_from_ivec(cached).is_ok_and(|x| value.is_same(&x));
+            if is_same && !self.is_expired(&entry)? {
+                plan.cached.push(key.to_string());
+            } else {
+                plan.update.push(Change {
+                    key: key.to_string(),
+                    from: self.record(&key)?.and_then(|x| x.version),
+                    to: entry.version(),
+                });
+            }
+        }
+
+        for key in self.orphaned()? {
+            plan.orphaned.push(E::Key::from_ivec(key).to_string());
+        }
+        Ok(plan)
+    }
+
+    /// Fetches and stores the results of the entries selected by the filter, and of their
+    /// dependencies, to the db
+    ///
+    /// Afterwards the lockfile is updated, or with `FetchMode::Locked` every entry is checked
+    /// against it
+    pub async fn async_fetch_filtered(&mut self, filter: &Filter<E>) -> Result<()> {
+        self.fetch_report(filter).await?.into_result()?;
+        Ok(())
+    }
+
+    /// Fetches the entries selected by the filter, and their dependencies, and reports what
+    /// happened to each of them
+    ///
+    /// Unlike `async_fetch_filtered` an entry failing doesn't fail the fetch, its error is in
+    /// the report. With `set_transactional` the fetched values are only stored if every entry
+    /// succeeded, and the report shows whether they were committed or rolled back
+    pub async fn fetch_report(&mut self, filter: &Filter<E>) -> Result<FetchReport> {
+        let entries = self.resolve(filter)?;
+        let locked = self.locked(&entries)?;
         let mut tasks = Vec::new();
-        for entry in (*self.entries).clone() {
+        for (entry, locked) in entries.iter().cloned().zip(locked) {
             let fetcher = self.clone();
             tasks.push(tokio::spawn(async move {
-                fetcher.handle_entry(entry.clone()).await
+                let _permit = match &fetcher.concurrency {
+                    Some(semaphore) => Some(semaphore.acquire().await?),
+                    None => None,
+                };
+                let staged = fetcher.stage(entry, locked).await?;
+                if let (false, Some(staged)) = (fetcher.transactional, &staged) {
+                    fetcher.commit(std::slice::from_ref(staged))?;
+                }
+                Ok::<_, anyhow::Error>(staged)
             }));
         }
 
-        join_all(tasks).await.into_iter().try_for_each(|x| x?)?;
+        let mut report = FetchReport::default();
+        let mut staged = Vec::new();
+        for (entry, result) in entries.iter().zip(join_all(tasks).await) {
+            let outcome = match result.map_err(Into::into).and_then(|x| x) {
+                Ok(Some(x)) => {
+                    staged.push(x);
+                    Outcome::Fetched
+                }
+                Ok(None) => Outcome::Cached,
+                Err(e) => Outcome::Failed(format!("{:#}", e)),
+            };
+            report.outcomes.push((entry.key().to_string(), outcome));
+        }
 
-        Ok(())
+        if self.transactional {
+            report.commit = if report.failed().next().is_some() {
+                warn!(
+                    "Rolled back, none of the {} fetched values were stored",
+                    staged.len()
+                );
+                Commit::RolledBack
+            } else {
+                self.commit(&staged)?;
+                Commit::Committed
+            };
+        }
+        if report.is_success() {
+            (report.pruned, report.evicted) = self.after_fetch(&entries)?;
+        }
+        Ok(report)
     }
 
     #[cfg(feature = "unstable")]
     /// Fetches and stores all results to the db synchronously and in parallel
     pub fn sync_fetch(&mut self) -> Result<()> {
         let entries = self.entries.clone();
+        let locked = self.locked(&entries)?;
 
         let results: Vec<Result<()>> = entries
             .par_iter()
-            .map(|entry| self.handle_entry_sync(entry.clone()))
+            .zip(locked)
+            .map(|(entry, locked)| self.handle_entry_sync(entry.clone(), locked))
             .collect();
 
         results.into_iter().try_for_each(|x| x)?;
 
+        self.after_fetch(&entries)?;
+        Ok(())
+    }
+
+    /// Prunes orphaned keys if `auto_prune` is set, evicts values over the quota and updates
+    /// the lockfile, returning the pruned and evicted keys
+    fn after_fetch(&self, fetched: &[E]) -> Result<(Vec<String>, Vec<String>)> {
+        let pruned = match self.auto_prune {
+            true => self.prune(false)?,
+            false => Vec::new(),
+        };
+        for key in &pruned {
+            info!("{} pruned", key);
+        }
+        let evicted = self.gc()?;
+        for key in &evicted {
+            info!("{} evicted", key);
+        }
+        // The values are stored already, so a lockfile that can't be written doesn't fail the fetch
+        if let Err(e) = self.update_lockfile(fetched) {
+            warn!("Failed to update {}: {:#}", LOCKFILE, e);
+        }
+        Ok((pruned, evicted))
+    }
+
+    /// Returns the locked entry of each entry with `FetchMode::Locked`, failing if the
+    /// lockfile or any of the entries is missing from it
+    fn locked(&self, entries: &[E]) -> Result<Vec<Option<LockedEntry>>> {
+        if self.fetch_mode != FetchMode::Locked {
+            return Ok(vec![None; entries.len()]);
+        }
+        let lockfile = self
+            .lockfile()?
+            .ok_or_else(|| anyhow!("FetchMode::Locked needs a lockfile, fetch once without it"))?;
+        entries
+            .iter()
+            .map(|entry| {
+                let key = entry.key();
+                match lockfile.get(&key.to_string()) {
+                    Some(locked) => Ok(Some(locked.clone())),
+                    None => bail!("{} is not in {}", key, LOCKFILE),
+                }
+            })
+            .collect()
+    }
+
+    /// Reads the lockfile, returning `None` if there's no lockfile
+    pub fn lockfile(&self) -> Result<Option<Lockfile>> {
+        match &self.lock_path {
+            Some(path) if path.exists() => Ok(Some(Lockfile::from_file(path)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Records the fetched entries in the lockfile with `FetchMode::Update`
+    ///
+    /// Entries that were removed are dropped from the lockfile, the other entries are kept
+    fn update_lockfile(&self, fetched: &[E]) -> Result<()> {
+        let Some(path) = self
+            .lock_path
+            .as_ref()
+            .filter(|_| self.fetch_mode == FetchMode::Update)
+        else {
+            return Ok(());
+        };
+        let mut lockfile = self.lockfile()?.unwrap_or_default();
+        let keys = self
+            .entries
+            .iter()
+            .map(|x| x.key().to_string())
+            .collect::<HashSet<_>>();
+        lockfile.retain(|x| keys.contains(&x.key));
+        for entry in fetched {
+            let key = entry.key();
+            if let Some(record) = self.record(&key)? {
+                // Entries are locked at their own url, git urls at the commit that was fetched
+                let url = meta::resolved_url(&entry.value().url(), git::pinned_commit(&record.url));
+                lockfile.insert(LockedEntry::new(&key, url, &record));
+            }
+        }
+        lockfile.save(path)
+    }
+
+    /// Returns the record of the cached response of the key
+    ///
+    /// Responses cached before records were kept get one from the cached value, without a
+    /// version and with the url that served it as the final url
+    pub fn record<K: EntryKey>(&self, key: &K) -> Result<Option<Record>> {
+        self.record_of(&key.bytes())
+    }
+
+    fn record_of(&self, key: &[u8]) -> Result<Option<Record>> {
+        if let Some(record) = self.meta.get(key)?.and_then(|x| Record::from_bytes(&x)) {
+            return Ok(Some(record));
+        }
+        let Some(cached) = self.cached(key)? else {
+            return Ok(None);
+        };
+        let value = E::Value::try_from_ivec(cached)
+            .with_context(|| format!("Failed to read {}", E::Key::from_ivec(key.into())))?;
+        let url = self.rewrite(&value.url());
+        let url = meta::resolved_url(&url, value.revision());
+        Ok(Some(Record::new(url.clone(), url, None, &value.response())))
+    }
+
+    /// Returns when the cached value of the entry expires, `None` if it never does
+    ///
+    /// Values cached before fetch times were recorded have already expired
+    fn expires_at(&self, entry: &E) -> Result<Option<SystemTime>> {
+        let Some(ttl) = entry.ttl().or(self.ttl) else {
+            return Ok(None);
+        };
+        let fetched_at = self
+            .record_of(&entry.key().bytes())?
+            .and_then(|x| x.fetched_at);
+        Ok(Some(fetched_at.map_or(SystemTime::UNIX_EPOCH, |x| x + ttl)))
+    }
+
+    fn is_expired(&self, entry: &E) -> Result<bool> {
+        Ok(self
+            .expires_at(entry)?
+            .is_some_and(|x| x <= SystemTime::now()))
+    }
+
+    /// Returns the cached entries whose values expire within `within`, with when they expire,
+    /// soonest first
+    ///
+    /// With `Duration::ZERO` these are the entries the next fetch will fetch again because
+    /// they're stale. Entries without a TTL are never listed
+    pub fn due_for_revalidation(&self, within: Duration) -> Result<Vec<(E, SystemTime)>> {
+        let deadline = SystemTime::now() + within;
+        let mut due = Vec::new();
+        for entry in self.entries.iter() {
+            if !self.db.contains_key(entry.key().bytes())? {
+                continue;
+            }
+            if let Some(expires_at) = self.expires_at(entry)?.filter(|x| *x <= deadline) {
+                due.push((entry.clone(), expires_at));
+            }
+        }
+        due.sort_by_key(|(_, expires_at)| *expires_at);
+        Ok(due)
+    }
+
+    /// Changes the record of a cached key, returning false if the key isn't cached
+    fn modify_record<F: FnOnce(&mut Record)>(&self, key: &[u8], f: F) -> Result<bool> {
+        let Some(mut record) = self.record_of(key)? else {
+            return Ok(false);
+        };
+        f(&mut record);
+        self.meta.insert(key, record.to_bytes())?;
+        Ok(true)
+    }
+
+    /// Records that the cached value of the key was used
+    fn touch(&self, key: &[u8]) -> Result<()> {
+        self.modify_record(key, |x| x.accessed_at = Some(SystemTime::now()))?;
         Ok(())
     }
 
@@ -465,18 +1538,18 @@ impl<E: Entry + Clone + Send + Sync + 'static + for<'de> Deserialize<'de>> Fetch
         self.db
             .iter()
             .map(|x| {
-                let (key_iv, value_iv) = x.unwrap();
-                let key = K::from_ivec(key_iv);
-                Ok((key, V::from_ivec(value_iv)))
+                let (key_iv, value_iv) = x?;
+                let value = self.decode(&key_iv, value_iv)?;
+                Ok((K::from_ivec(key_iv), V::try_from_ivec(value)?))
             })
             .collect()
     }
 
     /// Gets an entry from the db by key
     pub fn get<K: EntryKey, V: EntryValue>(&self, key: K) -> Result<Option<V>> {
-        if let Some(value_iv) = self.db.get(key.bytes())? {
-            let value = V::from_ivec(value_iv);
-            Ok(Some(value))
+        if let Some(value_iv) = self.cached(&key.bytes())? {
+            self.touch(&key.bytes())?;
+            Ok(Some(V::try_from_ivec(value_iv)?))
         } else {
             Ok(None)
         }
@@ -484,27 +1557,41 @@ impl<E: Entry + Clone + Send + Sync + 'static + for<'de> Deserialize<'de>> Fetch
 
     /// Removes an entry from the db by key
     pub fn remove<K: EntryKey>(&self, key: K) -> Result<()> {
-        self.db.remove(key.bytes())?;
+        self.forget(&key.bytes())?;
         Ok(())
     }
 
     /// Updates an entry in the db by key and new value
     pub fn update<K: EntryKey, V: EntryValue>(&self, key: K, value: V) -> Result<()> {
         if let Some(curr_val) = self.db.get(key.bytes())? {
-            let cv_bytes = curr_val.to_vec();
-            let cv = V::from_ivec(curr_val);
+            let cv = V::try_from_ivec(self.decode(&key.bytes(), curr_val.clone())?)?;
             if !value.is_same(&cv) {
-                let _ =
-                    self.db
-                        .compare_and_swap(key.bytes(), Some(cv_bytes), Some(value.bytes()))?;
+                let value = self.encode(&key.bytes(), value.bytes(), None)?;
+                let _ = self
+                    .db
+                    .compare_and_swap(key.bytes(), Some(curr_val), Some(value))?;
+                // The record is taken from the new value when it's next needed
+                self.meta.remove(key.bytes())?;
             }
         }
         Ok(())
     }
 
     /// Writes all the fetched data to the specified directory
+    ///
+    /// Git snapshots are unpacked into a directory named after the entry's key.
+    /// An entry is written once the entries it depends on are, and entries that don't
+    /// depend on each other are written concurrently.
     pub async fn write_all(&self, dir: PathBuf) -> Result<()> {
-        let total_entries = self.entries.len();
+        self.write_filtered(dir, &Filter::All).await
+    }
+
+    /// Writes the fetched data of the entries selected by the filter, and of their dependencies,
+    /// to the specified directory
+    pub async fn write_filtered(&self, dir: PathBuf, filter: &Filter<E>) -> Result<()> {
+        let entries = self.resolve(filter)?;
+        let (graph, _) = deps::graph(&entries);
+        let total_entries = entries.len();
         let progress_bar = Arc::new(Mutex::new(ProgressBar::new(total_entries as u64)));
         progress_bar.lock().await.set_style(
             ProgressStyle::default_bar()
@@ -513,42 +1600,774 @@ impl<E: Entry + Clone + Send + Sync + 'static + for<'de> Deserialize<'de>> Fetch
                 .progress_chars("##-"),
         );
 
-        let mut tasks = Vec::new();
-        for entry in (*self.entries).clone() {
+        if !dir.exists() {
+            create_dir(&dir).await?;
+        }
+        let mut jobs = Vec::new();
+        for entry in entries {
             let key = entry.key();
-            let value_vec = self.db.get(key.bytes())?.unwrap().to_vec();
-            let value: E::Value = E::Value::from_bytes(&value_vec);
-            let resp = value.response();
-            let file_name = Url::parse(&value.url())?
-                .path_segments()
-                .unwrap()
-                .last()
-                .unwrap()
-                .to_string();
-            let path = dir.join(&file_name);
-            if !dir.exists() {
-                create_dir(&dir).await?;
-            }
-            let bytes = resp.to_vec();
+            let Some(value_vec) = self.cached(&key.bytes())? else {
+                bail!("{} is not cached", key);
+            };
+            self.touch(&key.bytes())?;
+            jobs.push(Some(write_job::<E::Value>(&key, &value_vec, &dir)?));
+        }
+
+        let result = deps::run(&graph, |index| {
+            let (file_name, path, bytes, is_git) = jobs[index].take().unwrap();
             let pb_clone = Arc::clone(&progress_bar);
-            tasks.push(tokio::spawn(async move {
+            async move {
                 pb_clone
                     .lock()
                     .await
                     .set_message(format!("Writing: {}", file_name));
-                let result = tokio::fs::write(&path, bytes).await;
+                let result = write(path, bytes, is_git).await;
                 pb_clone.lock().await.inc(1);
                 result
-            }));
-        }
-
-        let results = join_all(tasks).await;
+            }
+        })
+        .await;
         progress_bar
             .lock()
             .await
             .finish_with_message("All files written");
 
-        results.into_iter().try_for_each(|x| x?)?;
+        result
+    }
+}
+
+// Cache Management
+impl<E: Entry + Clone + Send + Sync + 'static + for<'de> Deserialize<'de>> Fetcher<E> {
+    /// Pin the cached value of the key so it's never evicted, failing if it isn't cached
+    pub fn pin<K: EntryKey>(&self, key: K) -> Result<()> {
+        if !self.modify_record(&key.bytes(), |x| x.pinned = true)? {
+            bail!("{} is not cached", key);
+        }
+        Ok(())
+    }
+
+    /// Unpin the cached value of the key, failing if it isn't cached
+    pub fn unpin<K: EntryKey>(&self, key: K) -> Result<()> {
+        if !self.modify_record(&key.bytes(), |x| x.pinned = false)? {
+            bail!("{} is not cached", key);
+        }
         Ok(())
     }
+
+    /// Evicts the least recently used cached values until the cache is within its quota,
+    /// returning the evicted keys
+    ///
+    /// Pinned values and values held by a rollback are never evicted, so the cache stays over
+    /// its quota if they alone exceed it
+    pub fn gc(&self) -> Result<Vec<String>> {
+        if self.quota.is_unlimited() {
+            return Ok(Vec::new());
+        }
+        let mut candidates = Vec::new();
+        for item in self.db.iter() {
+            let (key, value) = item?;
+            let record = self.meta.get(&key)?.and_then(|x| Record::from_bytes(&x));
+            candidates.push(Candidate {
+                bytes: value.len() as u64,
+                accessed_at: record.as_ref().and_then(|x| x.accessed_at),
+                pinned: record.is_some_and(|x| x.pinned || x.held),
+                key,
+            });
+        }
+
+        let mut keys = Vec::new();
+        for key in cache::evict(&self.quota, candidates) {
+            self.forget(&key)?;
+            keys.push(E::Key::from_ivec(key).to_string());
+        }
+        Ok(keys)
+    }
+
+    /// Returns the number of cached values, the bytes they take and how many are pinned
+    pub fn stats(&self) -> Result<Stats> {
+        let mut stats = Stats {
+            size_on_disk: self.db.size_on_disk()?,
+            ..Default::default()
+        };
+        for item in self.db.iter() {
+            let (key, value) = item?;
+            stats.entries += 1;
+            stats.bytes += value.len() as u64;
+            let (header, value) = self.decrypt(&key, value)?;
+            stats.encrypted += usize::from(header.encrypted);
+            stats.raw_bytes += match header.compressed {
+                true => compress::raw_len(&value)?,
+                false => value.len() as u64,
+            };
+            stats.compressed += usize::from(header.compressed);
+            if let Some(record) = self.meta.get(&key)?.and_then(|x| Record::from_bytes(&x)) {
+                stats.pinned += usize::from(record.pinned);
+            }
+        }
+        for item in self.history.iter() {
+            let (_, value) = item?;
+            stats.history += 1;
+            stats.history_bytes += value.len() as u64;
+        }
+        Ok(stats)
+    }
+}
+
+// History
+impl<E: Entry + Clone + Send + Sync + 'static + for<'de> Deserialize<'de>> Fetcher<E> {
+    /// Returns a new id for a version of a value, ids start at 1
+    fn next_id(&self) -> Result<u64> {
+        Ok(self.db.generate_id()? + 1)
+    }
+
+    /// Adds moving the cached value of the key into its history to the batch, dropping the
+    /// oldest versions beyond `keep_versions`
+    fn keep_history(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        restored: Option<&[u8]>,
+        batch: &mut Batch,
+    ) -> Result<()> {
+        if self.keep_versions == 0 {
+            return Ok(());
+        }
+        let Some(mut record) = self.record_of(key)? else {
+            return Ok(());
+        };
+        if record.id == 0 {
+            record.id = self.next_id()?;
+        }
+        record.pinned = false;
+        record.held = false;
+        let new = history::key(key, record.id);
+        batch.insert(new.as_slice(), history::encode(&record, value));
+
+        let mut versions = self
+            .history
+            .scan_prefix(history::prefix(key))
+            .keys()
+            .map(|x| x.map(|x| x.to_vec()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        // The version being restored leaves history in the same transaction
+        versions.retain(|x| Some(x.as_slice()) != restored);
+        versions.push(new);
+        versions.sort();
+        for old in versions
+            .iter()
+            .take(versions.len().saturating_sub(self.keep_versions))
+        {
+            batch.remove(old.as_slice());
+        }
+        Ok(())
+    }
+
+    /// Returns the cached versions of the key, the current version first and then the
+    /// previous versions from newest to oldest
+    pub fn versions<K: EntryKey>(&self, key: K) -> Result<Vec<Version>> {
+        let key = key.bytes();
+        let mut versions = Vec::new();
+        if let Some(record) = self.record_of(&key)? {
+            versions.push(Version {
+                id: record.id,
+                current: true,
+                record,
+            });
+        }
+        for item in self.history.scan_prefix(history::prefix(&key)).rev() {
+            let (_, bytes) = item?;
+            let (record, _) = history::decode(&bytes)?;
+            versions.push(Version {
+                id: record.id,
+                current: false,
+                record,
+            });
+        }
+        Ok(versions)
+    }
+
+    /// Rolls the key back to the version before the cached one without any network access,
+    /// returning it
+    ///
+    /// Rolling back again goes further back, versions newer than the cached one are kept
+    /// and can be rolled forward to with `rollback_to`
+    ///
+    /// The restored value is held so fetches keep it, `release` lets them update it again
+    pub fn rollback<K: EntryKey>(&self, key: K) -> Result<Version> {
+        let key_bytes = key.bytes();
+        // Values cached before ids were kept are newer than all of their versions
+        let current = self
+            .record_of(&key_bytes)?
+            .map(|x| x.id)
+            .filter(|x| *x != 0)
+            .unwrap_or(u64::MAX);
+        let Some(previous) = self
+            .history
+            .range(history::key(&key_bytes, 0)..history::key(&key_bytes, current))
+            .keys()
+            .next_back()
+        else {
+            bail!("{} has no previous version", key);
+        };
+        self.restore(&key_bytes, &key, history::id(&previous?))
+    }
+
+    /// Rolls the key back (or forward) to one of its versions without any network access,
+    /// see `rollback`
+    pub fn rollback_to<K: EntryKey>(&self, key: K, id: u64) -> Result<Version> {
+        self.restore(&key.bytes(), &key, id)
+    }
+
+    /// Releases the value of the key held by a rollback so fetches update it again, failing if
+    /// it isn't cached
+    pub fn release<K: EntryKey>(&self, key: K) -> Result<()> {
+        if !self.modify_record(&key.bytes(), |x| x.held = false)? {
+            bail!("{} is not cached", key);
+        }
+        Ok(())
+    }
+
+    fn restore(&self, key: &[u8], name: &dyn std::fmt::Display, id: u64) -> Result<Version> {
+        let now = SystemTime::now();
+        if let Some(record) = self.record_of(key)?.filter(|x| x.id == id) {
+            self.modify_record(key, |x| x.held = true)?;
+            return Ok(Version {
+                id,
+                current: true,
+                record: Record {
+                    held: true,
+                    ..record
+                },
+            });
+        }
+        let version = history::key(key, id);
+        let Some(bytes) = self.history.get(&version)? else {
+            bail!("{} has no version {}", name, id);
+        };
+        let (mut record, value) = history::decode(&bytes)?;
+        record.pinned = self.record_of(key)?.is_some_and(|x| x.pinned);
+        record.held = true;
+        record.accessed_at = Some(now);
+
+        // The current value becomes history so it can be rolled forward to again
+        self.commit(&[Staged {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            record: record.clone(),
+            restored: Some(version),
+        }])?;
+        Ok(Version {
+            id,
+            current: true,
+            record,
+        })
+    }
+
+    /// Writes one of the versions of the key to the directory without rolling back to it,
+    /// see `versions`
+    pub async fn write_version<K: EntryKey>(&self, dir: PathBuf, key: K, id: u64) -> Result<()> {
+        let key_bytes = key.bytes();
+        let current = self.record_of(&key_bytes)?.is_some_and(|x| x.id == id);
+        let value = match current {
+            true => self.cached(&key_bytes)?.map(|x| x.to_vec()),
+            false => match self.history.get(history::key(&key_bytes, id))? {
+                Some(bytes) => {
+                    let value = history::decode(&bytes)?.1.into();
+                    Some(self.decode(&key_bytes, value)?.to_vec())
+                }
+                None => None,
+            },
+        };
+        let Some(value) = value else {
+            bail!("{} has no version {}", key, id);
+        };
+        if !dir.exists() {
+            create_dir(&dir).await?;
+        }
+        let (_, path, bytes, is_git) = write_job::<E::Value>(&key, &value, &dir)?;
+        write(path, bytes, is_git).await
+    }
+}
+
+// Integrity
+impl<E: Entry + Clone + Send + Sync + 'static + for<'de> Deserialize<'de>> Fetcher<E> {
+    /// Checks that the cache is intact, returning the problems found and how they were repaired
+    ///
+    /// Every cached value and previous version is read and re-hashed against its record,
+    /// every record is read, and records whose value is missing and versions of keys that
+    /// aren't cached are found. A bad value is repaired with its newest intact previous version
+    /// when it has one, and with `Repair::Refetch` the entries whose values were deleted are
+    /// fetched again
+    pub async fn verify(&mut self, repair: Repair) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let mut fixes = Vec::new();
+        for item in self.db.iter() {
+            let (key, value) = item?;
+            report.values += 1;
+            let name = E::Key::from_ivec(key.clone()).to_string();
+            let record = match self.meta.get(&key)? {
+                Some(bytes) => {
+                    let record = Record::from_bytes(&bytes);
+                    if record.is_none() {
+                        report.issues.push(Issue::BadRecord { key: name.clone() });
+                        fixes.push(Fix::Record(key.clone(), false));
+                    }
+                    record
+                }
+                None => None,
+            };
+            if let Some(error) = self.check_value(&key, value, record.as_ref()) {
+                report.issues.push(Issue::Corrupt { key: name, error });
+                fixes.push(Fix::Restore(key));
+            }
+        }
+        for key in self.meta.iter().keys() {
+            let key = key?;
+            if !self.db.contains_key(&key)? {
+                let name = E::Key::from_ivec(key.clone()).to_string();
+                report.issues.push(Issue::Missing { key: name });
+                fixes.push(Fix::Record(key, true));
+            }
+        }
+        for item in self.history.iter() {
+            let (version, bytes) = item?;
+            report.versions += 1;
+            let Some((key, id)) = history::split(&version) else {
+                let key = String::from_utf8_lossy(&version).to_string();
+                report.issues.push(Issue::Orphaned { key, id: 0 });
+                fixes.push(Fix::Version(version));
+                continue;
+            };
+            let name = E::Key::from_ivec(IVec::from(key)).to_string();
+            if !self.db.contains_key(key)? {
+                report.issues.push(Issue::Orphaned { key: name, id });
+                fixes.push(Fix::Version(version));
+                continue;
+            }
+            let error = match history::decode(&bytes) {
+                Ok((record, value)) => self.check_value(key, IVec::from(value), Some(&record)),
+                Err(e) => Some(e.to_string()),
+            };
+            if let Some(error) = error {
+                report.issues.push(Issue::BadVersion {
+                    key: name,
+                    id,
+                    error,
+                });
+                fixes.push(Fix::Version(version));
+            }
+        }
+        if repair == Repair::None {
+            return Ok(report);
+        }
+
+        let mut refetch = Vec::new();
+        for fix in fixes {
+            match fix {
+                Fix::Restore(key) => {
+                    let name = E::Key::from_ivec(key.clone()).to_string();
+                    match self.intact_version(&key)? {
+                        Some((version, mut record, value)) => {
+                            record.pinned = self.record_of(&key)?.is_some_and(|x| x.pinned);
+                            self.replace_from_history(&key, &version, &record, &value)?;
+                            report.restored.push((name, record.id));
+                        }
+                        None => {
+                            self.db.remove(&key)?;
+                            self.meta.remove(&key)?;
+                            report.deleted.push(name);
+                            refetch.push(key.to_vec());
+                        }
+                    }
+                }
+                Fix::Record(key, missing) => {
+                    self.meta.remove(&key)?;
+                    if missing {
+                        refetch.push(key.to_vec());
+                    }
+                }
+                Fix::Version(version) => {
+                    self.history.remove(version)?;
+                }
+            }
+        }
+        refetch.retain(|key| self.entries.iter().any(|x| x.key().bytes() == *key));
+        if repair == Repair::Refetch && !refetch.is_empty() {
+            report.refetch = Some(self.fetch_report(&Filter::Keys(refetch)).await?);
+        }
+        Ok(report)
+    }
+
+    /// Returns the history key, record and stored value of the newest previous version of the
+    /// key that matches its record
+    fn intact_version(&self, key: &[u8]) -> Result<Option<(IVec, Record, Vec<u8>)>> {
+        for item in self.history.scan_prefix(history::prefix(key)).rev() {
+            let (version, bytes) = item?;
+            let Ok((record, value)) = history::decode(&bytes) else {
+                continue;
+            };
+            if self
+                .check_value(key, IVec::from(value), Some(&record))
+                .is_none()
+            {
+                return Ok(Some((version, record, value.to_vec())));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Moves a previous version of the key in place of its cached value, which is dropped
+    fn replace_from_history(
+        &self,
+        key: &[u8],
+        version: &[u8],
+        record: &Record,
+        value: &[u8],
+    ) -> Result<()> {
+        let db: &Tree = &self.db;
+        (db, &self.meta, &self.history)
+            .transaction(|(db, meta, history)| {
+                db.insert(key, value)?;
+                meta.insert(key, record.to_bytes())?;
+                history.remove(version)?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError| anyhow!("Failed to restore a version: {:?}", e))
+    }
+
+    /// Returns why a stored value doesn't match its record, if it doesn't
+    fn check_value(&self, key: &[u8], stored: IVec, record: Option<&Record>) -> Option<String> {
+        let bytes = match self.decode(key, stored) {
+            Ok(bytes) => bytes,
+            Err(e) => return Some(format!("unreadable stored value ({:#})", e)),
+        };
+        let value = match E::Value::try_from_ivec(bytes) {
+            Ok(value) => value,
+            Err(e) => return Some(format!("unreadable value ({})", e)),
+        };
+        let record = record?;
+        let response = value.response();
+        if response.len() as u64 != record.size {
+            return Some(format!(
+                "size is {} instead of {}",
+                response.len(),
+                record.size
+            ));
+        }
+        let sha256 = sha256_hex(&response);
+        if sha256 != record.sha256 {
+            return Some(format!(
+                "checksum is {} instead of {}",
+                sha256, record.sha256
+            ));
+        }
+        None
+    }
+}
+
+/// Returns the file name and path a cached value is written to, its response and whether
+/// it's a git snapshot, which is unpacked into a directory named after the key
+fn write_job<V: EntryValue>(
+    key: &dyn std::fmt::Display,
+    value: &[u8],
+    dir: &Path,
+) -> Result<(String, PathBuf, Vec<u8>, bool)> {
+    let value =
+        V::try_from_ivec(IVec::from(value)).with_context(|| format!("Failed to read {}", key))?;
+    let url = value.url();
+    let is_git = git::is_git_url(&url);
+    let file_name = if is_git {
+        key.to_string()
+    } else {
+        Url::parse(&url)?
+            .path_segments()
+            .and_then(|mut x| x.next_back())
+            .filter(|x| !x.is_empty())
+            .ok_or_else(|| anyhow!("No file name to write {} to in {}", key, url))?
+            .to_string()
+    };
+    let path = dir.join(&file_name);
+    Ok((file_name, path, value.response().to_vec(), is_git))
+}
+
+async fn write(path: PathBuf, bytes: Vec<u8>, is_git: bool) -> Result<()> {
+    if is_git {
+        tokio::task::spawn_blocking(move || git::unpack(&bytes, &path)).await?
+    } else {
+        tokio::fs::write(&path, bytes).await.map_err(Into::into)
+    }
+}
+
+/// Returns the spec to request `url` with for an entry whose own url is `own`, without the
+/// spec's headers unless `url` has the same origin (so mirrors and rewritten hosts don't get them)
+fn scoped_spec(spec: &RequestSpec, own: &str, url: &str) -> RequestSpec {
+    let origin = |url: &str| {
+        Url::parse(url).ok().map(|x| {
+            let host = x.host_str().map(String::from);
+            (x.scheme().to_string(), host, x.port_or_known_default())
+        })
+    };
+    let same_origin = origin(own).is_some_and(|own| Some(own) == origin(url));
+    match same_origin {
+        true => spec.clone(),
+        false => RequestSpec {
+            headers: BTreeMap::new(),
+            ..spec.clone()
+        },
+    }
+}
+
+/// A fetched value waiting to be stored
+struct Staged {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    record: Record,
+    /// The history key of the version this value is restored from, removed when it's stored
+    restored: Option<Vec<u8>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::SimplePackage;
+    use crate::testing::{response, serve, Server};
+    use crate::val::SimpleValue;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn fetcher(dir: &Path, packages: Vec<SimplePackage>) -> Fetcher<SimplePackage> {
+        FetcherBuilder::in_memory(dir.join("db"))
+            .build_with_entries(packages)
+            .unwrap()
+    }
+
+    /// Serves `/a-<version>.bin` with the version as the body
+    async fn versioned() -> Server {
+        serve(|path| {
+            let version = path.trim_start_matches("/a-").trim_end_matches(".bin");
+            response(200, &[], version)
+        })
+        .await
+    }
+
+    fn package(server: &Server, version: &str) -> SimplePackage {
+        SimplePackage::new("a", version, &format!("{}/a-{}.bin", server.url, version))
+    }
+
+    /// Fetches each version of `a` in turn
+    async fn fetch_versions(
+        fetcher: &mut Fetcher<SimplePackage>,
+        server: &Server,
+        versions: &[&str],
+    ) {
+        for version in versions {
+            fetcher.set_entries(vec![package(server, version)]);
+            fetcher.async_fetch().await.unwrap();
+        }
+    }
+
+    fn cached(fetcher: &Fetcher<SimplePackage>) -> String {
+        let value = fetcher
+            .get::<String, SimpleValue>("a".into())
+            .unwrap()
+            .unwrap();
+        String::from_utf8(value.response().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rolls_back_and_forward() {
+        let server = versioned().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut fetcher = fetcher(dir.path(), Vec::new());
+        fetcher.set_keep_versions(5);
+        fetch_versions(&mut fetcher, &server, &["1", "2", "3"]).await;
+        let newest = fetcher.versions("a".to_string()).unwrap()[0].id;
+
+        let version = fetcher.rollback("a".to_string()).unwrap();
+        assert_eq!(version.record.version.as_deref(), Some("2"));
+        assert_eq!(cached(&fetcher), "2");
+        fetcher.rollback("a".to_string()).unwrap();
+        assert_eq!(cached(&fetcher), "1");
+        assert!(fetcher.rollback("a".to_string()).is_err());
+
+        let version = fetcher.rollback_to("a".to_string(), newest).unwrap();
+        assert_eq!(version.record.version.as_deref(), Some("3"));
+        assert_eq!(cached(&fetcher), "3");
+
+        // Every version is still kept exactly once
+        let versions = fetcher.versions("a".to_string()).unwrap();
+        let mut kept = versions
+            .iter()
+            .map(|x| x.record.version.clone().unwrap())
+            .collect::<Vec<_>>();
+        kept.sort();
+        assert_eq!(kept, ["1", "2", "3"]);
+        assert!(versions[0].current && versions[0].record.held);
+    }
+
+    #[tokio::test]
+    async fn keeps_a_held_value_until_released() {
+        let server = versioned().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut fetcher = fetcher(dir.path(), Vec::new());
+        fetcher.set_keep_versions(5);
+        fetch_versions(&mut fetcher, &server, &["1", "2"]).await;
+
+        fetcher.rollback("a".to_string()).unwrap();
+        fetcher.async_fetch().await.unwrap();
+        assert_eq!(cached(&fetcher), "1");
+
+        fetcher.release("a".to_string()).unwrap();
+        fetcher.async_fetch().await.unwrap();
+        assert_eq!(cached(&fetcher), "2");
+    }
+
+    /// Fetches `a` at version 1 into a new db, writing the lockfile in the directory
+    async fn locked(dir: &Path, server: &Server) -> Fetcher<SimplePackage> {
+        let mut fetcher = fetcher(dir, vec![package(server, "1")]);
+        fetcher.set_lock_path(Some(dir.join(LOCKFILE)));
+        fetcher.async_fetch().await.unwrap();
+        fetcher.set_fetch_mode(FetchMode::Locked);
+        fetcher
+    }
+
+    #[tokio::test]
+    async fn writes_no_lockfile_by_default() {
+        let server = versioned().await;
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("pkgs.toml"), "").unwrap();
+        let mut fetcher: Fetcher<SimplePackage> = FetcherBuilder::new(
+            dir.path().join("pkgs.toml"),
+            Mode::Toml,
+            dir.path().join("db"),
+        )
+        .build()
+        .await
+        .unwrap();
+        fetcher.set_entries(vec![package(&server, "1")]);
+        fetcher.async_fetch().await.unwrap();
+        assert!(fetcher.lock_path().is_none());
+        assert!(!dir.path().join(LOCKFILE).exists());
+    }
+
+    #[tokio::test]
+    async fn refuses_to_change_a_locked_version_or_url() {
+        let server = versioned().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut fetcher = locked(dir.path(), &server).await;
+
+        fetcher.set_entries(vec![package(&server, "2")]);
+        let error = fetcher.async_fetch().await.unwrap_err();
+        assert!(error.to_string().contains("locks 1"), "{:#}", error);
+
+        let moved = SimplePackage::new("a", "1", &format!("{}/b-1.bin", server.url));
+        fetcher.set_entries(vec![moved]);
+        let error = fetcher.async_fetch().await.unwrap_err();
+        assert!(error.to_string().contains("resolves to"), "{:#}", error);
+
+        // Nothing changed, and the lockfile still locks the first fetch
+        assert_eq!(cached(&fetcher), "1");
+        let lockfile = fetcher.lockfile().unwrap().unwrap();
+        assert_eq!(lockfile.get("a").unwrap().version.as_deref(), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_a_mirror_serving_the_locked_content() {
+        let tampered = Arc::new(AtomicBool::new(false));
+        let serve_tampered = tampered.clone();
+        let server = serve(move |_| match serve_tampered.load(Ordering::SeqCst) {
+            true => response(200, &[], "tampered"),
+            false => response(200, &[], "1"),
+        })
+        .await;
+        let mirror = versioned().await;
+        let dir = tempfile::tempdir().unwrap();
+        drop(locked(dir.path(), &server).await);
+        tampered.store(true, Ordering::SeqCst);
+
+        // A new db has nothing cached, so the locked entry is fetched again
+        let mirrored = package(&server, "1").with_mirrors(vec![format!("{}/a-1.bin", mirror.url)]);
+        let mut fetcher = FetcherBuilder::in_memory(dir.path().join("new"))
+            .lock_path(dir.path().join(LOCKFILE))
+            .fetch_mode(FetchMode::Locked)
+            .build_with_entries(vec![mirrored])
+            .unwrap();
+        fetcher.async_fetch().await.unwrap();
+        assert_eq!(cached(&fetcher), "1");
+        assert_eq!(server.requests().len(), 2);
+        assert_eq!(mirror.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stores_nothing_when_a_transactional_fetch_fails() {
+        let server = versioned().await;
+        let missing = serve(|_| response(404, &[], "")).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut fetcher = fetcher(dir.path(), Vec::new());
+        fetcher.set_transactional(true);
+        fetch_versions(&mut fetcher, &server, &["1"]).await;
+
+        let broken = SimplePackage::new("b", "1", &format!("{}/b.bin", missing.url));
+        let other = SimplePackage::new("c", "1", &format!("{}/a-c.bin", server.url));
+        fetcher.set_entries(vec![package(&server, "2"), broken, other]);
+        let report = fetcher.fetch_report(&Filter::All).await.unwrap();
+
+        assert_eq!(report.commit, Commit::RolledBack);
+        assert_eq!(report.failed().count(), 1);
+        assert!(!report.is_success());
+        assert_eq!(cached(&fetcher), "1");
+        assert_eq!(
+            fetcher
+                .record(&"a".to_string())
+                .unwrap()
+                .unwrap()
+                .version
+                .as_deref(),
+            Some("1")
+        );
+        assert!(fetcher
+            .get::<String, SimpleValue>("c".into())
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn sends_the_entry_headers_only_to_its_own_url() {
+        let server = serve(|_| response(500, &[], "")).await;
+        let mirror = versioned().await;
+        let package: SimplePackage = serde_json::from_value(serde_json::json!({
+            "name": "a",
+            "version": "1",
+            "url": format!("{}/a-1.bin", server.url),
+            "mirrors": [format!("{}/a-1.bin", mirror.url)],
+            "headers": {"X-Token": "secret"},
+        }))
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut fetcher = fetcher(dir.path(), vec![package]);
+        fetcher.async_fetch().await.unwrap();
+
+        let has_token = |head: &String| head.to_lowercase().contains("x-token: secret");
+        assert!(server.requests().iter().all(has_token));
+        assert!(!mirror.requests().iter().any(has_token));
+        assert_eq!(cached(&fetcher), "1");
+    }
+
+    #[tokio::test]
+    async fn fails_on_a_redirection_without_a_location() {
+        let server = serve(|_| response(300, &[], "choices")).await;
+        let dir = tempfile::tempdir().unwrap();
+        let package = SimplePackage::new("a", "1.0", &format!("{}/a.bin", server.url));
+        let mut fetcher = fetcher(dir.path(), vec![package]);
+
+        let error = fetcher.fetch(FetchMethod::Async).await.unwrap_err();
+        assert!(
+            error.to_string().contains("without a location"),
+            "{:#}",
+            error
+        );
+        assert!(fetcher
+            .get::<String, SimpleValue>("a".into())
+            .unwrap()
+            .is_none());
+    }
 }
@@ -0,0 +1,289 @@
+use crate::git;
+use crate::layer::Layer;
+use crate::package::Mode;
+use regex::Regex;
+use serde_json::Value;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use toml_edit::{ImDocument, Item};
+use url::Url;
+
+/// Lints checked by `Config::validate`
+///
+/// - `DuplicateKey`: A file defines two packages with the same key, which would overwrite each
+///   other in the db (error)
+/// - `InvalidUrl`: A url or mirror can't be parsed (error)
+/// - `UnsupportedScheme`: A url or mirror uses a scheme that can't be fetched (error)
+/// - `InsecureUrl`: A url or mirror is fetched without TLS, such as `http://` (warning)
+/// - `VersionMismatch`: The url mentions a version but not the package's version (warning)
+/// - `MissingDependency`: A package depends on a key that isn't a package (error)
+/// - `DependencyCycle`: Packages depend on each other in a cycle (error)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Lint {
+    DuplicateKey,
+    InvalidUrl,
+    UnsupportedScheme,
+    InsecureUrl,
+    VersionMismatch,
+    MissingDependency,
+    DependencyCycle,
+}
+
+impl Lint {
+    /// Every lint in the catalogue
+    pub const ALL: [Lint; 7] = [
+        Lint::DuplicateKey,
+        Lint::InvalidUrl,
+        Lint::UnsupportedScheme,
+        Lint::InsecureUrl,
+        Lint::VersionMismatch,
+        Lint::MissingDependency,
+        Lint::DependencyCycle,
+    ];
+
+    /// Returns the name of the lint, such as `duplicate-key`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::DuplicateKey => "duplicate-key",
+            Lint::InvalidUrl => "invalid-url",
+            Lint::UnsupportedScheme => "unsupported-scheme",
+            Lint::InsecureUrl => "insecure-url",
+            Lint::VersionMismatch => "version-mismatch",
+            Lint::MissingDependency => "missing-dependency",
+            Lint::DependencyCycle => "dependency-cycle",
+        }
+    }
+
+    /// Returns the severity the lint is reported with
+    pub fn severity(&self) -> Severity {
+        match self {
+            Lint::DuplicateKey
+            | Lint::InvalidUrl
+            | Lint::UnsupportedScheme
+            | Lint::MissingDependency
+            | Lint::DependencyCycle => Severity::Error,
+            Lint::InsecureUrl | Lint::VersionMismatch => Severity::Warning,
+        }
+    }
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Warning => f.write_str("warning"),
+            Severity::Error => f.write_str("error"),
+        }
+    }
+}
+
+/// A position in a config file, the line and column start at 1
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Span {
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    fn at(path: &Path, contents: &str, offset: usize) -> Self {
+        let before = &contents[..offset];
+        let line_start = before.rfind('\n').map(|x| x + 1).unwrap_or(0);
+        Self {
+            path: path.to_path_buf(),
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if !self.path.as_os_str().is_empty() {
+            write!(f, "{}:", self.path.display())?;
+        }
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// A problem found by `Config::validate`
+///
+/// - `package`: The key of the package the problem is in
+/// - `span`: Where the problem is, if the config was read from a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub lint: Lint,
+    pub severity: Severity,
+    pub message: String,
+    pub package: Option<String>,
+    pub span: Option<Span>,
+}
+
+impl Diagnostic {
+    pub fn new<S: Into<String>>(lint: Lint, message: S) -> Self {
+        Self {
+            lint,
+            severity: lint.severity(),
+            message: message.into(),
+            package: None,
+            span: None,
+        }
+    }
+
+    /// Set the key of the package the problem is in
+    pub fn with_package<S: ToString>(mut self, package: S) -> Self {
+        self.package = Some(package.to_string());
+        self
+    }
+
+    /// Set where the problem is
+    pub fn with_span(mut self, span: Option<Span>) -> Self {
+        self.span = span;
+        self
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(span) = &self.span {
+            write!(f, "{}: ", span)?;
+        }
+        write!(f, "{}[{}]: {}", self.severity, self.lint, self.message)
+    }
+}
+
+/// Checks that a url (or a mirror) can be parsed and fetched
+pub fn check_url(url: &str) -> Vec<Diagnostic> {
+    let (inner, schemes): (&str, &[&str]) = match git::parse_url(url) {
+        Ok((repo, _)) => (repo, &["https", "http", "ssh", "git", "file"]),
+        Err(_) => (url, &["https", "http", "s3"]),
+    };
+    // `git@host:path` repositories are ssh
+    if git::is_git_url(url) && !inner.contains("://") && inner.contains(':') {
+        return Vec::new();
+    }
+    let parsed = match Url::parse(inner) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            return vec![Diagnostic::new(
+                Lint::InvalidUrl,
+                format!("Invalid url {}: {}", url, e),
+            )]
+        }
+    };
+    let scheme = parsed.scheme();
+    if !schemes.contains(&scheme) {
+        return vec![Diagnostic::new(
+            Lint::UnsupportedScheme,
+            format!("Unsupported scheme `{}` in {}", scheme, url),
+        )];
+    }
+    if matches!(scheme, "http" | "git") {
+        return vec![Diagnostic::new(
+            Lint::InsecureUrl,
+            format!("{} is fetched without TLS", url),
+        )];
+    }
+    Vec::new()
+}
+
+/// Checks that a url mentioning a version mentions the package's version
+pub fn check_version(version: &str, url: &str) -> Option<Diagnostic> {
+    static VERSION: OnceLock<Regex> = OnceLock::new();
+    let bare = version.trim_start_matches('v');
+    if bare.is_empty() || url.contains(bare) {
+        return None;
+    }
+    let mentioned = VERSION
+        .get_or_init(|| Regex::new(r"\d+\.\d+(\.\d+)?").unwrap())
+        .find(url)?;
+    Some(Diagnostic::new(
+        Lint::VersionMismatch,
+        format!(
+            "Version {} doesn't appear in the url, which mentions {}",
+            version,
+            mentioned.as_str()
+        ),
+    ))
+}
+
+/// Returns the span of a field of the package at `index` in the file, or of the package itself
+pub fn locate(layer: &Layer, index: usize, field: Option<&str>) -> Option<Span> {
+    let offset = match layer.mode {
+        Mode::Toml => toml_offset(&layer.contents, index, field),
+        _ => search_offset(&layer.contents, &layer.value, index, field),
+    }?;
+    Some(Span::at(&layer.path, &layer.contents, offset))
+}
+
+fn toml_offset(contents: &str, index: usize, field: Option<&str>) -> Option<usize> {
+    let doc = ImDocument::parse(contents).ok()?;
+    let (package, field) = match doc.get("packages")? {
+        Item::ArrayOfTables(tables) => {
+            let table = tables.get(index)?;
+            let field = field.and_then(|x| table.get(x)).and_then(Item::span);
+            (table.span(), field)
+        }
+        Item::Value(toml_edit::Value::Array(array)) => {
+            let table = array.get(index)?.as_inline_table()?;
+            let field = field.and_then(|x| table.get(x)).and_then(|x| x.span());
+            (table.span(), field)
+        }
+        _ => return None,
+    };
+    field.or(package).map(|x| x.start)
+}
+
+/// Finds the package by searching for the strings of each package in order, for formats
+/// without spans
+fn search_offset(
+    contents: &str,
+    value: &Value,
+    index: usize,
+    field: Option<&str>,
+) -> Option<usize> {
+    let packages = value.get("packages")?.as_array()?;
+    let mut cursor = contents.find("packages").unwrap_or(0);
+    for (i, package) in packages.iter().enumerate().take(index + 1) {
+        let mut start = None;
+        for (name, value) in package.as_object()? {
+            let Some(s) = first_string(value) else {
+                continue;
+            };
+            let Some(found) = contents[cursor..].find(s) else {
+                continue;
+            };
+            let offset = cursor + found;
+            cursor = offset + s.len();
+            start.get_or_insert(offset);
+            if i == index && field == Some(name.as_str()) {
+                return Some(offset);
+            }
+        }
+        if i == index {
+            return start;
+        }
+    }
+    None
+}
+
+fn first_string(value: &Value) -> Option<&str> {
+    match value {
+        Value::String(s) => Some(s),
+        Value::Array(values) => values.iter().find_map(first_string),
+        Value::Object(map) => map.values().find_map(first_string),
+        _ => None,
+    }
+}
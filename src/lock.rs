@@ -0,0 +1,166 @@
+use crate::edit::write_atomic;
+use crate::meta::Record;
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::str::FromStr;
+
+/// File name of the lockfile, written next to the config file by default
+pub const LOCKFILE: &str = "quickfetch.lock";
+
+/// Version of the lockfile format
+const FORMAT: u32 = 1;
+
+const HEADER: &str =
+    "# This file is generated by quickfetch, it is not meant to be edited by hand\n\n";
+
+/// A locked entry, what its key resolved to and what was fetched
+///
+/// - `key`: The key of the entry
+/// - `version`: The version of the entry, if it has one
+/// - `url`: The url of the entry, before mirrors and rewrites (git urls are pinned to the commit,
+///   as `git+<repo url>#<commit>`)
+/// - `final_url`: The url that served it after mirrors, rewrites and redirects, recorded for
+///   reference only as redirect targets often carry expiring signatures
+/// - `size`: The size of the response in bytes
+/// - `sha256`: The SHA-256 of the response as a lowercase hex string
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockedEntry {
+    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub url: String,
+    pub final_url: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl LockedEntry {
+    pub fn new<S: ToString>(key: S, url: String, record: &Record) -> Self {
+        Self {
+            key: key.to_string(),
+            version: record.version.clone(),
+            url,
+            final_url: record.final_url.clone(),
+            size: record.size,
+            sha256: record.sha256.clone(),
+        }
+    }
+}
+
+/// The lockfile (`quickfetch.lock`), a TOML file with a `[[package]]` table per entry
+///
+/// The entries are sorted by key and their fields are always in the same order,
+/// so a change to one entry only changes its own lines.
+///
+/// ```toml
+/// version = 1
+///
+/// [[package]]
+/// key = "mufiz-rpm-x86_64"
+/// version = "0.6.0"
+/// url = "https://github.com/Mustafif/MufiZ/releases/download/v0.6.0/mufiz-0.6.0-1.x86_64.rpm"
+/// final_url = "https://objects.githubusercontent.com/..."
+/// size = 1048576
+/// sha256 = "..."
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lockfile {
+    version: u32,
+    #[serde(default, rename = "package")]
+    entries: Vec<LockedEntry>,
+}
+
+impl Default for Lockfile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lockfile {
+    pub fn new() -> Self {
+        Self {
+            version: FORMAT,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Reads a lockfile
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+        contents
+            .parse()
+            .map_err(|e| anyhow!("Failed to parse {}: {}", path.display(), e))
+    }
+
+    /// Returns the locked entries, sorted by key
+    pub fn entries(&self) -> &[LockedEntry] {
+        &self.entries
+    }
+
+    /// Returns the locked entry with the key
+    pub fn get(&self, key: &str) -> Option<&LockedEntry> {
+        self.position(key).ok().map(|index| &self.entries[index])
+    }
+
+    /// Adds or replaces the locked entry with the same key, returning the previous one
+    pub fn insert(&mut self, entry: LockedEntry) -> Option<LockedEntry> {
+        match self.position(&entry.key) {
+            Ok(index) => Some(std::mem::replace(&mut self.entries[index], entry)),
+            Err(index) => {
+                self.entries.insert(index, entry);
+                None
+            }
+        }
+    }
+
+    /// Removes the locked entry with the key, returning it if it existed
+    pub fn remove(&mut self, key: &str) -> Option<LockedEntry> {
+        let index = self.position(key).ok()?;
+        Some(self.entries.remove(index))
+    }
+
+    /// Keeps only the locked entries the closure returns true for
+    pub fn retain<F: FnMut(&LockedEntry) -> bool>(&mut self, f: F) {
+        self.entries.retain(f);
+    }
+
+    fn position(&self, key: &str) -> std::result::Result<usize, usize> {
+        self.entries.binary_search_by(|x| x.key.as_str().cmp(key))
+    }
+
+    /// Serializes the lockfile to TOML
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(format!("{}{}", HEADER, toml::to_string(self)?))
+    }
+
+    /// Writes the lockfile atomically, leaving the file untouched if it wouldn't change
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let contents = self.to_toml()?;
+        if std::fs::read_to_string(path).ok().as_deref() == Some(contents.as_str()) {
+            return Ok(());
+        }
+        write_atomic(path, contents.as_bytes())
+    }
+}
+
+impl FromStr for Lockfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut lockfile: Self = toml::from_str(s)?;
+        if lockfile.version > FORMAT {
+            bail!(
+                "Lockfile version {} is newer than the supported version {}",
+                lockfile.version,
+                FORMAT
+            );
+        }
+        lockfile.entries.sort_by(|a, b| a.key.cmp(&b.key));
+        lockfile.entries.dedup_by(|a, b| a.key == b.key);
+        Ok(lockfile)
+    }
+}
@@ -0,0 +1,77 @@
+use crate::sha256_hex;
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+/// Name of the sled tree holding the records, next to the cached values
+pub(crate) const TREE: &str = "meta";
+
+/// What is known about a cached response, recorded when it is fetched
+///
+/// - `url`: The url it was fetched from, after mirrors and rewrites (git urls are pinned to the
+///   commit, as `git+<repo url>#<commit>`)
+/// - `final_url`: The url that served it after following redirects
+/// - `version`: The version of the entry when it was fetched
+/// - `size`: The size of the response in bytes
+/// - `sha256`: The SHA-256 of the response as a lowercase hex string
+/// - `fetched_at`: When it was fetched
+/// - `accessed_at`: When it was last fetched, read with `get` or written with `write_all`
+/// - `pinned`: Whether it's exempt from eviction when the cache is over its quota
+/// - `id`: Identifies this version of the value, see `Fetcher::versions`
+/// - `etag`: The ETag sent with it, compared on cache hits of S3 objects
+/// - `held`: Whether fetches keep it instead of fetching the entry again, set when it's restored
+///   with `Fetcher::rollback` until `Fetcher::release`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub url: String,
+    pub final_url: String,
+    pub version: Option<String>,
+    pub size: u64,
+    pub sha256: String,
+    #[serde(default)]
+    pub fetched_at: Option<SystemTime>,
+    #[serde(default)]
+    pub accessed_at: Option<SystemTime>,
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub id: u64,
+    #[serde(default)]
+    pub etag: Option<String>,
+    #[serde(default)]
+    pub held: bool,
+}
+
+impl Record {
+    pub fn new(url: String, final_url: String, version: Option<String>, response: &[u8]) -> Self {
+        Self {
+            url,
+            final_url,
+            version,
+            size: response.len() as u64,
+            sha256: sha256_hex(response),
+            fetched_at: None,
+            accessed_at: None,
+            pinned: false,
+            id: 0,
+            etag: None,
+            held: false,
+        }
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    /// Records that can't be read (such as ones from an older version) are treated as missing
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        serde_json::from_slice(bytes).ok()
+    }
+}
+
+/// Returns the url to record for a url, git urls are pinned to the commit they resolved to
+pub(crate) fn resolved_url(url: &str, revision: Option<String>) -> String {
+    match (crate::git::parse_url(url), revision) {
+        (Ok((repo, _)), Some(commit)) => format!("git+{}#{}", repo, commit),
+        _ => url.to_string(),
+    }
+}
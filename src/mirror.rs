@@ -0,0 +1,45 @@
+use serde::{Deserialize, Serialize};
+
+/// A global mirror rule that adds an alternative url for every url starting with `prefix`
+///
+/// For example `MirrorRule::new("https://github.com/", "https://artifactory.internal/github/")`
+/// makes every GitHub download try the internal Artifactory first
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MirrorRule {
+    prefix: String,
+    replacement: String,
+}
+
+impl MirrorRule {
+    pub fn new<S: Into<String>>(prefix: S, replacement: S) -> Self {
+        Self {
+            prefix: prefix.into(),
+            replacement: replacement.into(),
+        }
+    }
+
+    /// Returns the mirrored url if the rule applies to it
+    pub fn apply(&self, url: &str) -> Option<String> {
+        url.strip_prefix(&self.prefix)
+            .map(|rest| format!("{}{}", self.replacement, rest))
+    }
+}
+
+/// Returns the urls to try in order
+///
+/// Each url is preceded by its rewrites from the mirror rules, and duplicates are removed
+pub fn candidates<I: IntoIterator<Item = String>>(rules: &[MirrorRule], urls: I) -> Vec<String> {
+    let mut candidates: Vec<String> = Vec::new();
+    for url in urls {
+        for candidate in rules
+            .iter()
+            .filter_map(|rule| rule.apply(&url))
+            .chain(std::iter::once(url.clone()))
+        {
+            if !candidates.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+    }
+    candidates
+}
@@ -1,9 +1,21 @@
-use quickfetch_traits::Entry;
+use anyhow::{anyhow, bail};
+use quickfetch_traits::{Entry, EntryKey, EntryValue, RequestSpec};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::fs::read_to_string;
 
-use crate::val::{GHValue, SimpleValue};
+use crate::auth::StaticProvider;
+use crate::deps;
+use crate::edit::{merge_toml, write_atomic};
+use crate::git;
+use crate::layer::{self, Layer, Provenance};
+use crate::lint::{self, Diagnostic, Lint, Span};
+use crate::rewrite::RewriteRule;
+use crate::val::{GHValue, GitValue, SimpleValue};
+use crate::vars;
 /// A Minimal Package Implementation
 ///
 /// This module provides a minimal package implementation
@@ -12,11 +24,109 @@ use crate::val::{GHValue, SimpleValue};
 /// - a name (String)
 /// - a semantic version (String)
 /// - a URL (String)
+///
+/// And optionally:
+/// - tags (or groups) to select the package by (Vec<String>)
+/// - the names of the packages it depends on (Vec<String>)
+/// - mirror URLs to fall back to in order (Vec<String>)
+/// - the SHA-256 of the artifact (String)
+/// - the HTTP method, headers, query and body of the request
+/// - a request timeout such as `"30s"` or `"5m"`
+/// - a time-to-live of the cached artifact such as `"1d"`, for artifacts like nightlies that
+///   change without a version bump
+/// - whether to compress the cached artifact at rest (bool)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SimplePackage {
     name: String,
     version: String,
     url: String,
+    #[serde(default, alias = "groups", skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    depends: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mirrors: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    method: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    query: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    timeout: Option<Duration>,
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    ttl: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compress: Option<bool>,
+}
+
+impl SimplePackage {
+    pub fn new<S: Into<String>>(name: S, version: S, url: S) -> Self {
+        Self {
+            name: name.into(),
+            version: version.into(),
+            url: url.into(),
+            tags: Vec::new(),
+            depends: Vec::new(),
+            mirrors: Vec::new(),
+            sha256: None,
+            method: None,
+            headers: BTreeMap::new(),
+            query: BTreeMap::new(),
+            body: None,
+            timeout: None,
+            ttl: None,
+            compress: None,
+        }
+    }
+
+    /// Set the tags (or groups) to select the package by
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Set the names of the packages it depends on
+    pub fn with_depends(mut self, depends: Vec<String>) -> Self {
+        self.depends = depends;
+        self
+    }
+
+    /// Set the mirror URLs to fall back to in order
+    pub fn with_mirrors(mut self, mirrors: Vec<String>) -> Self {
+        self.mirrors = mirrors;
+        self
+    }
+
+    /// Set the expected SHA-256 (hex) of the artifact
+    pub fn with_sha256<S: Into<String>>(mut self, sha256: S) -> Self {
+        self.sha256 = Some(sha256.into());
+        self
+    }
+
+    /// Set how long the cached artifact stays fresh
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Set whether to compress the cached artifact at rest
+    pub fn with_compress(mut self, compress: bool) -> Self {
+        self.compress = Some(compress);
+        self
+    }
 }
 
 impl Entry for SimplePackage {
@@ -30,6 +140,89 @@ impl Entry for SimplePackage {
     fn value(&self) -> Self::Value {
         SimpleValue::new(self.version.clone(), self.url.clone())
     }
+
+    fn mirrors(&self) -> Vec<String> {
+        self.mirrors.clone()
+    }
+
+    fn tags(&self) -> Vec<String> {
+        self.tags.clone()
+    }
+
+    fn depends(&self) -> Vec<Self::Key> {
+        self.depends.clone()
+    }
+
+    fn checksum(&self) -> Option<String> {
+        self.sha256.clone()
+    }
+
+    fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    fn compress(&self) -> Option<bool> {
+        self.compress
+    }
+
+    fn request(&self) -> RequestSpec {
+        RequestSpec {
+            method: self.method.clone(),
+            headers: self.headers.clone(),
+            query: self.query.clone(),
+            body: self.body.clone().map(String::into_bytes),
+            timeout: self.timeout,
+        }
+    }
+
+    fn version(&self) -> Option<String> {
+        Some(self.version.clone())
+    }
+}
+
+/// A package whose version can be changed with `Config::update_version`
+pub trait Versioned: Entry {
+    /// Set the version of the package
+    fn set_version(&mut self, version: &str);
+}
+
+impl Versioned for SimplePackage {
+    /// Sets the version, also replacing the old version where it appears in the url and mirrors
+    fn set_version(&mut self, version: &str) {
+        if !self.version.is_empty() {
+            let old = self.version.as_str();
+            self.url = replace_version(&self.url, old, version);
+            for mirror in &mut self.mirrors {
+                *mirror = replace_version(mirror, old, version);
+            }
+        }
+        self.version = version.to_string();
+    }
+}
+
+/// Replaces `old` in `text` where it's a whole version, not part of a longer one
+/// (`1.0` is replaced in `v1.0/foo-1.0.tar.gz`, but not in `foo-1.0.10` or `foo-11.0`)
+fn replace_version(text: &str, old: &str, new: &str) -> String {
+    let mut replaced = String::new();
+    let mut last = 0;
+    for (start, _) in text.match_indices(old) {
+        let end = start + old.len();
+        let before = text[..start].chars().next_back();
+        let mut after = text[end..].chars();
+        let whole = !before.is_some_and(|x| x.is_ascii_digit() || x == '.')
+            && match after.next() {
+                Some('.') => !after.next().is_some_and(|x| x.is_ascii_digit()),
+                Some(x) => !x.is_ascii_digit(),
+                None => true,
+            };
+        if whole {
+            replaced.push_str(&text[last..start]);
+            replaced.push_str(new);
+            last = end;
+        }
+    }
+    replaced.push_str(&text[last..]);
+    replaced
 }
 
 /// A Minimal GH Package Implementation
@@ -47,6 +240,17 @@ pub struct GHPackage {
     asset: String,
 }
 
+impl GHPackage {
+    pub fn new<S: Into<String>>(owner: S, repo: S, tag: S, asset: S) -> Self {
+        Self {
+            owner: owner.into(),
+            repo: repo.into(),
+            tag: tag.into(),
+            asset: asset.into(),
+        }
+    }
+}
+
 impl Entry for GHPackage {
     type Key = String;
     type Value = GHValue;
@@ -66,48 +270,424 @@ impl Entry for GHPackage {
             self.asset.clone(),
         )
     }
+
+    fn version(&self) -> Option<String> {
+        Some(self.tag.clone())
+    }
+}
+
+impl Versioned for GHPackage {
+    /// Sets the release tag
+    fn set_version(&mut self, version: &str) {
+        self.tag = version.to_string();
+    }
+}
+
+/// A Git Repository Snapshot Package Implementation
+///
+/// Fetches a shallow snapshot of the repository and caches a tarball of its tree,
+/// which `Fetcher::write_all` unpacks into a directory named after the package
+///
+/// It requires:
+/// - a name (String)
+/// - a repository URL (String)
+///
+/// And optionally one of (defaults to the remote `HEAD`):
+/// - a rev, the full commit hash (String)
+/// - a tag (String)
+/// - a branch (String)
+///
+/// And optionally:
+/// - tags (or groups) to select the package by (Vec<String>)
+/// - the names of the packages it depends on (Vec<String>)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitPackage {
+    name: String,
+    url: String,
+    rev: Option<String>,
+    tag: Option<String>,
+    branch: Option<String>,
+    #[serde(default, alias = "groups", skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    depends: Vec<String>,
+}
+
+impl GitPackage {
+    /// Creates a package tracking the remote `HEAD`
+    pub fn new<S: Into<String>>(name: S, url: S) -> Self {
+        Self {
+            name: name.into(),
+            url: url.into(),
+            rev: None,
+            tag: None,
+            branch: None,
+            tags: Vec::new(),
+            depends: Vec::new(),
+        }
+    }
+
+    /// Pin the package to a full commit hash
+    pub fn with_rev<S: Into<String>>(mut self, rev: S) -> Self {
+        self.rev = Some(rev.into());
+        self
+    }
+
+    /// Track a tag
+    pub fn with_tag<S: Into<String>>(mut self, tag: S) -> Self {
+        self.tag = Some(tag.into());
+        self
+    }
+
+    /// Track a branch
+    pub fn with_branch<S: Into<String>>(mut self, branch: S) -> Self {
+        self.branch = Some(branch.into());
+        self
+    }
+
+    /// Set the tags (or groups) to select the package by
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Set the names of the packages it depends on
+    pub fn with_depends(mut self, depends: Vec<String>) -> Self {
+        self.depends = depends;
+        self
+    }
+}
+
+impl Entry for GitPackage {
+    type Key = String;
+    type Value = GitValue;
+
+    fn key(&self) -> Self::Key {
+        self.name.clone()
+    }
+
+    fn value(&self) -> Self::Value {
+        let reference = self
+            .rev
+            .clone()
+            .or_else(|| self.tag.clone())
+            .or_else(|| self.branch.clone())
+            .unwrap_or_else(|| "HEAD".to_string());
+        GitValue::new(self.url.clone(), reference)
+    }
+
+    fn version(&self) -> Option<String> {
+        self.rev.clone().or_else(|| self.tag.clone())
+    }
+
+    fn tags(&self) -> Vec<String> {
+        self.tags.clone()
+    }
+
+    fn depends(&self) -> Vec<Self::Key> {
+        self.depends.clone()
+    }
+}
+
+impl Versioned for GitPackage {
+    /// Tracks the tag instead of any rev or branch
+    fn set_version(&mut self, version: &str) {
+        self.rev = None;
+        self.branch = None;
+        self.tag = Some(version.to_string());
+    }
 }
 
 /// A Minimal Config Implementation
 ///
-/// The Config struct is used to store a list of Packages (generically PK).
-/// We provide methods of reading from both JSON and TOML files
+/// The Config struct is used to store a list of Packages (generically PK),
+/// the url rewrite rules applied before fetching them and credentials by host.
+/// We provide methods of reading and writing JSON, TOML, YAML and RON files
+///
+/// A config file can include other config files, which it is layered on top of:
+///
+/// ```toml
+/// include = ["common.toml", "team/*.toml"]
+///
+/// # Only the key and the overridden fields are needed to override an included package
+/// [[packages]]
+/// name = "foo"
+/// version = "1.3.0"
+/// ```
+///
+/// Strings in packages can reference the `[vars]` table with `${var}`, environment variables with
+/// `${env:NAME}` and the package's own `version` and `name` fields with `${version}` and `${name}`
+/// (`$${` is a literal `${`). Referencing anything undefined is an error.
+///
+/// ```toml
+/// [vars]
+/// releases = "https://github.com/Mustafif/MufiZ/releases/download"
+///
+/// [[packages]]
+/// name = "mufiz-rpm-x86_64"
+/// version = "0.6.0"
+/// url = "${releases}/v${version}/mufiz-${version}-1.x86_64.rpm"
+/// ```
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config<PK> {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    include: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    vars: BTreeMap<String, String>,
     packages: Vec<PK>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rewrites: Vec<RewriteRule>,
+    #[serde(default, skip_serializing_if = "StaticProvider::is_empty")]
+    credentials: StaticProvider,
+    #[serde(skip)]
+    provenance: HashMap<Vec<u8>, Provenance>,
+    /// Fields of the packages as written, before their references were resolved
+    #[serde(skip)]
+    templates: HashMap<Vec<u8>, Map<String, Value>>,
+    /// Files the config was read from
+    #[serde(skip)]
+    sources: Vec<Layer>,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Format of a config file
+///
+/// - `Json`: `.json`
+/// - `Toml`: `.toml`
+/// - `Yaml`: `.yaml` or `.yml`
+/// - `Ron`: `.ron`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mode {
     Json,
     Toml,
+    Yaml,
+    Ron,
+}
+
+impl Mode {
+    /// Returns the format matching the extension of the path
+    pub fn from_extension<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "json" => Some(Mode::Json),
+            "toml" => Some(Mode::Toml),
+            "yaml" | "yml" => Some(Mode::Yaml),
+            "ron" => Some(Mode::Ron),
+            _ => None,
+        }
+    }
+
+    /// Returns the format matching the extension of the path, or else detected from the contents
+    pub fn from_path_or_contents<P: AsRef<Path>>(path: P, contents: &str) -> anyhow::Result<Self> {
+        Self::from_extension(&path)
+            .or_else(|| Self::detect(contents))
+            .ok_or_else(|| anyhow!("Could not detect the format of {}", path.as_ref().display()))
+    }
+
+    /// Detects the format of the contents by finding the first format that parses it
+    ///
+    /// Formats are tried from the strictest to the most lenient (JSON, TOML, RON then YAML)
+    pub fn detect(contents: &str) -> Option<Self> {
+        if serde_json::from_str::<serde_json::Value>(contents).is_ok() {
+            Some(Mode::Json)
+        } else if toml::from_str::<toml::Table>(contents).is_ok() {
+            Some(Mode::Toml)
+        } else if ron::from_str::<ron::Value>(contents).is_ok() {
+            Some(Mode::Ron)
+        } else if serde_yaml::from_str::<serde_yaml::Mapping>(contents).is_ok() {
+            Some(Mode::Yaml)
+        } else {
+            None
+        }
+    }
+}
+
+impl<PK: Clone> From<Vec<PK>> for Config<PK> {
+    fn from(packages: Vec<PK>) -> Self {
+        Self::new(packages)
+    }
 }
 
 #[allow(dead_code)]
 impl<PK: Clone> Config<PK> {
-    /// Reads a configuration file (JSON or TOML) and returns a Config struct.
+    /// Creates a config from a list of packages, without any rewrites or credentials.
+    pub fn new(packages: Vec<PK>) -> Self {
+        Self {
+            include: Vec::new(),
+            vars: BTreeMap::new(),
+            packages,
+            rewrites: Vec::new(),
+            credentials: StaticProvider::default(),
+            provenance: HashMap::new(),
+            templates: HashMap::new(),
+            sources: Vec::new(),
+        }
+    }
+
+    /// Reads a configuration file (JSON, TOML, YAML or RON) and returns a Config struct.
+    ///
+    /// The files listed in `include` are read first and layered in order, with later files
+    /// overriding the fields of packages with the same key. Included files may be in any format.
+    /// The `${...}` references of the packages are then resolved.
     pub async fn from_file<P>(path: P, mode: Mode) -> anyhow::Result<Self>
     where
         P: AsRef<Path> + Send + Sync,
-        PK: for<'de> Deserialize<'de>,
+        PK: Entry + for<'de> Deserialize<'de>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let layers = tokio::task::spawn_blocking(move || layer::load(path, Some(mode))).await??;
+        Self::from_layers(&layers)
+    }
+
+    /// Reads a configuration file, detecting its format from the extension or the contents.
+    pub async fn from_path<P>(path: P) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path> + Send + Sync,
+        PK: Entry + for<'de> Deserialize<'de>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let layers = tokio::task::spawn_blocking(move || layer::load(path, None)).await??;
+        Self::from_layers(&layers)
+    }
+
+    /// Returns the key of a package as written, or `None` if it isn't a complete package
+    fn raw_key(package: &Value) -> Option<Vec<u8>>
+    where
+        PK: Entry + for<'de> Deserialize<'de>,
+    {
+        serde_json::from_value::<PK>(package.clone())
+            .ok()
+            .map(|x| x.key().bytes())
+    }
+
+    fn from_layers(layers: &[Layer]) -> anyhow::Result<Self>
+    where
+        PK: Entry + for<'de> Deserialize<'de>,
+    {
+        let key = Self::raw_key;
+        // Point into the file when a single file doesn't parse by itself either
+        let located = |e: anyhow::Error| match layers {
+            [layer] => Self::parse_str(&layer.contents, layer.mode)
+                .err()
+                .unwrap_or(e),
+            _ => e,
+        };
+        let (mut merged, mut provenance) = layer::merge(layers, key).map_err(located)?;
+
+        let vars = match merged.get("vars") {
+            Some(raw) => vars::resolve_vars(&serde_json::from_value(raw.clone())?)?,
+            None => BTreeMap::new(),
+        };
+        let mut templates = HashMap::new();
+        if let Some(Value::Array(packages)) = merged.get_mut("packages") {
+            for package in packages {
+                let raw_key = key(package);
+                let Value::Object(fields) = package else {
+                    continue;
+                };
+                let template = fields
+                    .iter()
+                    .filter(|(_, value)| vars::has_references(value))
+                    .map(|(field, value)| (field.clone(), value.clone()))
+                    .collect::<Map<_, _>>();
+                vars::resolve_package(fields, &vars)?;
+                let (Some(raw_key), Some(package_key)) = (raw_key, key(package)) else {
+                    continue;
+                };
+                // Provenance was recorded before the key was resolved
+                if raw_key != package_key {
+                    if let Some(record) = provenance.remove(&raw_key) {
+                        provenance.insert(package_key.clone(), record);
+                    }
+                }
+                if !template.is_empty() {
+                    templates.insert(package_key, template);
+                }
+            }
+        }
+
+        let mut config = serde_json::from_value::<Self>(merged).map_err(|e| located(e.into()))?;
+        config.provenance = provenance;
+        config.templates = templates;
+        config.sources = layers.to_vec();
+        Ok(config)
+    }
+
+    /// Parses a configuration in the given format and returns a Config struct.
+    ///
+    /// The `${...}` references of the packages are resolved, includes are only read by
+    /// `from_file` and `from_path`.
+    pub fn from_str(contents: &str, mode: Mode) -> anyhow::Result<Self>
+    where
+        PK: Entry + for<'de> Deserialize<'de>,
     {
-        // Read file contents
-        let contents = read_to_string(path.as_ref()).await?;
+        let layer = Layer {
+            path: PathBuf::new(),
+            mode,
+            contents: contents.to_string(),
+            value: layer::parse(contents, mode)?,
+        };
+        Self::from_layers(&[layer])
+    }
 
+    fn parse_str(contents: &str, mode: Mode) -> anyhow::Result<Self>
+    where
+        PK: for<'de> Deserialize<'de>,
+    {
         // Deserialize based on mode
         let data = match mode {
-            Mode::Json => serde_json::from_str::<Config<PK>>(&contents)?,
-            Mode::Toml => toml::from_str::<Config<PK>>(&contents)?,
+            Mode::Json => serde_json::from_str::<Config<PK>>(contents)?,
+            Mode::Toml => toml::from_str::<Config<PK>>(contents)?,
+            Mode::Yaml => serde_yaml::from_str::<Config<PK>>(contents)?,
+            Mode::Ron => ron::from_str::<Config<PK>>(contents)?,
         };
 
         Ok(data)
     }
 
+    /// Serializes the config in the given format.
+    pub fn to_string(&self, mode: Mode) -> anyhow::Result<String>
+    where
+        PK: Serialize,
+    {
+        Ok(match mode {
+            Mode::Json => serde_json::to_string_pretty(self)?,
+            Mode::Toml => toml::to_string_pretty(self)?,
+            Mode::Yaml => serde_yaml::to_string(self)?,
+            Mode::Ron => ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?,
+        })
+    }
+
+    /// Writes the config to a file in the given format, replacing the file atomically.
+    pub async fn to_file<P>(&self, path: P, mode: Mode) -> anyhow::Result<()>
+    where
+        P: AsRef<Path> + Send + Sync,
+        PK: Serialize,
+    {
+        let contents = self.to_string(mode)?;
+        let path = path.as_ref().to_path_buf();
+        tokio::task::spawn_blocking(move || write_atomic(path, contents.as_bytes())).await?
+    }
+
+    /// Writes the config to a file, choosing the format from the extension.
+    pub async fn to_path<P>(&self, path: P) -> anyhow::Result<()>
+    where
+        P: AsRef<Path> + Send + Sync,
+        PK: Serialize,
+    {
+        let mode = Mode::from_extension(&path).ok_or_else(|| {
+            anyhow!(
+                "Could not detect the format of {} from its extension",
+                path.as_ref().display()
+            )
+        })?;
+        self.to_file(path, mode).await
+    }
+
     /// Reads a JSON file and returns a Config struct.
     pub async fn from_json_file<P>(path: P) -> anyhow::Result<Self>
     where
         P: AsRef<Path> + Send + Sync,
-        PK: for<'de> Deserialize<'de>,
+        PK: Entry + for<'de> Deserialize<'de>,
     {
         Self::from_file(path, Mode::Json).await
     }
@@ -116,11 +696,29 @@ impl<PK: Clone> Config<PK> {
     pub async fn from_toml_file<P>(path: P) -> anyhow::Result<Self>
     where
         P: AsRef<Path> + Send + Sync,
-        PK: for<'de> Deserialize<'de>,
+        PK: Entry + for<'de> Deserialize<'de>,
     {
         Self::from_file(path, Mode::Toml).await
     }
 
+    /// Reads a YAML file and returns a Config struct.
+    pub async fn from_yaml_file<P>(path: P) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path> + Send + Sync,
+        PK: Entry + for<'de> Deserialize<'de>,
+    {
+        Self::from_file(path, Mode::Yaml).await
+    }
+
+    /// Reads a RON file and returns a Config struct.
+    pub async fn from_ron_file<P>(path: P) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path> + Send + Sync,
+        PK: Entry + for<'de> Deserialize<'de>,
+    {
+        Self::from_file(path, Mode::Ron).await
+    }
+
     /// Returns a reference to the list of packages.
     pub fn packages(&self) -> &[PK] {
         &self.packages
@@ -130,4 +728,536 @@ impl<PK: Clone> Config<PK> {
     pub fn packages_owned(&self) -> Vec<PK> {
         self.packages.clone()
     }
+
+    /// Returns the url rewrite rules.
+    pub fn rewrites(&self) -> &[RewriteRule] {
+        &self.rewrites
+    }
+
+    /// Returns the credentials by host.
+    pub fn credentials(&self) -> &StaticProvider {
+        &self.credentials
+    }
+
+    /// Returns the variables packages can reference, as written.
+    pub fn vars(&self) -> &BTreeMap<String, String> {
+        &self.vars
+    }
+
+    /// Returns the config files included by this config.
+    pub fn include(&self) -> &[String] {
+        &self.include
+    }
+}
+
+// Editing Methods
+impl<PK: Entry + Clone> Config<PK> {
+    fn position(&self, key: &PK::Key) -> Option<usize> {
+        let key = key.bytes();
+        self.packages.iter().position(|x| x.key().bytes() == key)
+    }
+
+    /// Returns the package with the key.
+    pub fn get(&self, key: &PK::Key) -> Option<&PK> {
+        self.position(key).map(|index| &self.packages[index])
+    }
+
+    /// Returns where the final values of the package came from when it was read from files.
+    ///
+    /// Packages inserted or replaced since have no provenance.
+    pub fn provenance(&self, key: &PK::Key) -> Option<&Provenance> {
+        self.provenance.get(&key.bytes())
+    }
+
+    /// Returns whether a package with the key exists.
+    pub fn contains(&self, key: &PK::Key) -> bool {
+        self.position(key).is_some()
+    }
+
+    /// Inserts a package, replacing the package with the same key in place.
+    ///
+    /// Returns the replaced package, new packages are added at the end.
+    pub fn insert(&mut self, package: PK) -> Option<PK> {
+        self.provenance.remove(&package.key().bytes());
+        match self.position(&package.key()) {
+            Some(index) => Some(std::mem::replace(&mut self.packages[index], package)),
+            None => {
+                self.packages.push(package);
+                None
+            }
+        }
+    }
+
+    /// Removes the package with the key, returning it if it existed.
+    pub fn remove(&mut self, key: &PK::Key) -> Option<PK> {
+        let index = self.position(key)?;
+        self.provenance.remove(&key.bytes());
+        self.templates.remove(&key.bytes());
+        Some(self.packages.remove(index))
+    }
+
+    /// Sets the version of the package with the key, returning its previous version.
+    pub fn update_version(&mut self, key: &PK::Key, version: &str) -> anyhow::Result<Option<String>>
+    where
+        PK: Versioned,
+    {
+        let Some(index) = self.position(key) else {
+            bail!("No package with the key {}", key);
+        };
+        let package = &mut self.packages[index];
+        let previous = package.version();
+        package.set_version(version);
+        Ok(previous)
+    }
+
+    /// Saves the config to a file in the given format, replacing the file atomically.
+    ///
+    /// When saving TOML over an existing config, only the packages and values that changed are
+    /// rewritten, so the comments, ordering and formatting of the file are kept.
+    ///
+    /// A config read with includes is saved as its top file, the included files are left alone.
+    /// Packages of the included files are only written to `path` as override tables holding the
+    /// fields that changed (plus the ones needed to tell which package they override), and
+    /// removing one of them from the config doesn't remove it from the included file.
+    ///
+    /// Fields are written with their `${...}` references as long as they still resolve to the
+    /// package's values, so bumping a version keeps a `${version}` in the url.
+    pub async fn save<P>(&self, path: P, mode: Mode) -> anyhow::Result<()>
+    where
+        P: AsRef<Path> + Send + Sync,
+        PK: Serialize + for<'de> Deserialize<'de>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let keys = |packages: &[PK]| packages.iter().map(|x| x.key().bytes()).collect::<Vec<_>>();
+        let layered = self.sources.len() > 1;
+        let (mut contents, new_keys) = match layered {
+            true => self.top_layer(mode)?,
+            false => (self.with_templates().to_string(mode)?, keys(&self.packages)),
+        };
+        if mode == Mode::Toml && path.exists() {
+            let existing = read_to_string(&path).await?;
+            // A file that doesn't hold a valid config is overwritten instead of merged
+            let old_keys = match layered {
+                true => self.top_layer_keys(&existing, mode).ok(),
+                false => Self::from_str(&existing, mode)
+                    .or_else(|_| Self::parse_str(&existing, mode))
+                    .ok()
+                    .map(|old| keys(&old.packages)),
+            };
+            if let Some(old_keys) = old_keys {
+                contents = merge_toml(&existing, &contents, &old_keys, &new_keys)?;
+            }
+        }
+        tokio::task::spawn_blocking(move || write_atomic(path, contents.as_bytes())).await?
+    }
+
+    /// Returns the top file of a config read with includes as it's saved, along with the keys of
+    /// its packages as written
+    fn top_layer(&self, mode: Mode) -> anyhow::Result<(String, Vec<Vec<u8>>)>
+    where
+        PK: Serialize + for<'de> Deserialize<'de>,
+    {
+        let top = self.sources.len() - 1;
+        let table = |(layer, index): &(usize, usize)| {
+            self.sources[*layer]
+                .value
+                .get("packages")?
+                .get(*index)?
+                .as_object()
+        };
+        let tables = |key: &[u8]| {
+            self.provenance
+                .get(key)
+                .map(|x| x.tables.as_slice())
+                .unwrap_or_default()
+        };
+
+        // The packages as the included files define them, with their keys as written
+        let mut bases = Vec::new();
+        for package in &self.packages {
+            let key = package.key().bytes();
+            let mut base = Map::new();
+            for fields in tables(&key).iter().filter(|x| x.0 < top).filter_map(table) {
+                base.extend(fields.clone());
+            }
+            if !base.is_empty() {
+                let raw_key = Self::raw_key(&Value::Object(base.clone())).unwrap_or(key.clone());
+                bases.push((key, raw_key, base));
+            }
+        }
+        // The packages a table could override when the file is read again
+        let overridden = |table: &Map<String, Value>| {
+            bases
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, raw_key, base))| {
+                    let mut merged = base.clone();
+                    merged.extend(table.clone());
+                    Self::raw_key(&Value::Object(merged)).as_ref() == Some(raw_key)
+                })
+                .map(|(position, _)| position)
+                .collect::<Vec<_>>()
+        };
+
+        let mut packages = Vec::new();
+        let mut keys = Vec::new();
+        for package in &self.with_templates().packages {
+            let key = package.key().bytes();
+            let Value::Object(fields) = serde_json::to_value(package)? else {
+                bail!("Package {} is not a table", package.key());
+            };
+            let Some(position) = bases.iter().position(|x| x.0 == key) else {
+                keys.push(Self::raw_key(&Value::Object(fields.clone())).unwrap_or(key));
+                packages.push(Value::Object(fields));
+                continue;
+            };
+            let (_, raw_key, base) = &bases[position];
+            let own = tables(&key)
+                .iter()
+                .filter(|x| x.0 == top)
+                .filter_map(table)
+                .flat_map(|x| x.keys())
+                .collect::<HashSet<_>>();
+            let mut table = fields
+                .iter()
+                .filter(|(field, value)| own.contains(field) || base.get(*field) != Some(value))
+                .map(|(field, value)| (field.clone(), value.clone()))
+                .collect::<Map<_, _>>();
+            if table.is_empty() {
+                continue;
+            }
+            let mut rest = base
+                .iter()
+                .filter(|(field, _)| !table.contains_key(*field))
+                .map(|(field, value)| (field.clone(), fields.get(field).unwrap_or(value).clone()))
+                .collect::<Vec<_>>()
+                .into_iter();
+            // Fields telling which package is overridden go first, as they would be written
+            let mut ordered = Map::new();
+            while overridden(&table) != [position] {
+                let Some((field, value)) = rest.next() else {
+                    break;
+                };
+                table.insert(field.clone(), value.clone());
+                ordered.insert(field, value);
+            }
+            ordered.extend(table);
+            keys.push(raw_key.clone());
+            packages.push(Value::Object(ordered));
+        }
+
+        let mut root = self.sources[top]
+            .value
+            .as_object()
+            .cloned()
+            .unwrap_or_default();
+        root.insert("packages".to_string(), Value::Array(packages));
+        Ok((layer::to_string(&Value::Object(root), mode)?, keys))
+    }
+
+    /// Returns the keys of the packages of the top file, as layered on the included files
+    fn top_layer_keys(&self, contents: &str, mode: Mode) -> anyhow::Result<Vec<Vec<u8>>>
+    where
+        PK: for<'de> Deserialize<'de>,
+    {
+        let top = self.sources.len() - 1;
+        let mut layers = self.sources[..top].to_vec();
+        layers.push(Layer {
+            path: self.sources[top].path.clone(),
+            mode,
+            contents: contents.to_string(),
+            value: layer::parse(contents, mode)?,
+        });
+        let (_, provenance) = layer::merge(&layers, Self::raw_key)?;
+        let count = layers[top]
+            .value
+            .get("packages")
+            .and_then(Value::as_array)
+            .map_or(0, Vec::len);
+        let mut keys = vec![Vec::new(); count];
+        for (key, record) in provenance {
+            for (_, index) in record.tables.iter().filter(|x| x.0 == top) {
+                keys[*index] = key.clone();
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Returns a copy of the config with the references of the packages restored
+    fn with_templates(&self) -> Self
+    where
+        PK: Serialize + for<'de> Deserialize<'de>,
+    {
+        let mut config = self.clone();
+        let Ok(vars) = vars::resolve_vars(&self.vars) else {
+            return config;
+        };
+        for package in &mut config.packages {
+            let Some(template) = self.templates.get(&package.key().bytes()) else {
+                continue;
+            };
+            let Ok(Value::Object(mut fields)) = serde_json::to_value(&*package) else {
+                continue;
+            };
+            for (field, raw) in template {
+                // Resolve the field as written against the package's current name and version
+                let mut check = ["name", "version"]
+                    .into_iter()
+                    .filter_map(|own| Some((own.to_string(), fields.get(own)?.clone())))
+                    .collect::<Map<_, _>>();
+                check.insert(field.clone(), raw.clone());
+                if vars::resolve_package(&mut check, &vars).is_ok()
+                    && check.get(field) == fields.get(field)
+                {
+                    fields.insert(field.clone(), raw.clone());
+                }
+            }
+            if let Ok(restored) = serde_json::from_value::<PK>(Value::Object(fields)) {
+                *package = restored;
+            }
+        }
+        config
+    }
+}
+
+// Validation
+impl<PK: Entry + Clone + for<'de> Deserialize<'de>> Config<PK> {
+    /// Checks the config for mistakes without any network access, see `Lint` for the catalogue
+    ///
+    /// When the config was read from files, each diagnostic points at the file, line and column
+    /// of the offending value.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+
+        // Packages with the same key in different files are overrides, within a file they're mistakes
+        for layer in &self.sources {
+            let Some(Value::Array(packages)) = layer.value.get("packages") else {
+                continue;
+            };
+            let mut seen = HashMap::new();
+            for (index, package) in packages.iter().enumerate() {
+                let Ok(package) = serde_json::from_value::<PK>(package.clone()) else {
+                    continue;
+                };
+                let key = package.key();
+                if let Some(first) = seen.insert(key.bytes(), index) {
+                    let first = lint::locate(layer, first, None)
+                        .map(|x| format!(" at {}", x))
+                        .unwrap_or_default();
+                    diagnostics.push(
+                        Diagnostic::new(
+                            Lint::DuplicateKey,
+                            format!("Package {} is already defined{}", key, first),
+                        )
+                        .with_package(&key)
+                        .with_span(lint::locate(layer, index, None)),
+                    );
+                }
+            }
+        }
+        if self.sources.is_empty() {
+            let mut seen = HashSet::new();
+            for package in &self.packages {
+                let key = package.key();
+                if !seen.insert(key.bytes()) {
+                    diagnostics.push(
+                        Diagnostic::new(
+                            Lint::DuplicateKey,
+                            format!("Package {} is already defined", key),
+                        )
+                        .with_package(&key),
+                    );
+                }
+            }
+        }
+
+        for package in &self.packages {
+            let key = package.key();
+            let url = package.value().url();
+            let urls = std::iter::once((url.clone(), "url"))
+                .chain(package.mirrors().into_iter().map(|x| (x, "mirrors")));
+            for (url, field) in urls {
+                for diagnostic in lint::check_url(&url) {
+                    diagnostics.push(
+                        diagnostic
+                            .with_package(&key)
+                            .with_span(self.span(&key, field)),
+                    );
+                }
+            }
+            if let (false, Some(version)) = (git::is_git_url(&url), package.version()) {
+                if let Some(diagnostic) = lint::check_version(&version, &url) {
+                    diagnostics.push(
+                        diagnostic
+                            .with_package(&key)
+                            .with_span(self.span(&key, "version")),
+                    );
+                }
+            }
+        }
+
+        let (graph, missing) = deps::graph(&self.packages);
+        for (index, dep) in missing {
+            let key = self.packages[index].key();
+            diagnostics.push(
+                Diagnostic::new(
+                    Lint::MissingDependency,
+                    format!("Package {} depends on {}, which isn't a package", key, dep),
+                )
+                .with_package(&key)
+                .with_span(self.span(&key, "depends")),
+            );
+        }
+        if let Some(cycle) = deps::find_cycle(&graph) {
+            let key = self.packages[cycle[0]].key();
+            let cycle = cycle
+                .iter()
+                .map(|x| self.packages[*x].key().to_string())
+                .collect::<Vec<_>>();
+            diagnostics.push(
+                Diagnostic::new(
+                    Lint::DependencyCycle,
+                    format!("Dependency cycle: {}", cycle.join(" -> ")),
+                )
+                .with_package(&key)
+                .with_span(self.span(&key, "depends")),
+            );
+        }
+        diagnostics
+    }
+
+    /// Returns the span of the field in the file that last set it, or of the package
+    fn span(&self, key: &PK::Key, field: &str) -> Option<Span> {
+        let provenance = self.provenance.get(&key.bytes())?;
+        let path = provenance.field(field);
+        let (layer, index) = provenance
+            .tables
+            .iter()
+            .rev()
+            .find(|(layer, _)| self.sources.get(*layer).map(|x| x.path.as_path()) == path)
+            .or(provenance.tables.first())?;
+        lint::locate(self.sources.get(*layer)?, *index, path.map(|_| field))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMON: &str = r#"[vars]
+releases = "https://example.com/releases"
+
+[[packages]]
+name = "foo"
+version = "1.0.0"
+url = "${releases}/foo-${version}.tar.gz"
+
+[[packages]]
+name = "bar"
+version = "2.0.0"
+url = "https://example.com/bar-2.0.0.tar.gz"
+"#;
+
+    const TOP: &str = r#"include = ["common.toml"]
+
+# Pinned for the release
+[[packages]]
+name = "bar"
+version = "2.1.0" # bumped by hand
+"#;
+
+    async fn layered() -> (tempfile::TempDir, PathBuf, Config<SimplePackage>) {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("common.toml"), COMMON).unwrap();
+        let top = dir.path().join("pkgs.toml");
+        std::fs::write(&top, TOP).unwrap();
+        let config = Config::from_file(&top, Mode::Toml).await.unwrap();
+        (dir, top, config)
+    }
+
+    #[tokio::test]
+    async fn saves_only_the_top_layer() {
+        let (dir, top, config) = layered().await;
+        config.save(&top, Mode::Toml).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&top).unwrap(), TOP);
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("common.toml")).unwrap(),
+            COMMON
+        );
+    }
+
+    #[tokio::test]
+    async fn saves_changes_to_included_packages_as_overrides() {
+        let (dir, top, mut config) = layered().await;
+        config.update_version(&"foo".to_string(), "1.1.0").unwrap();
+        config.update_version(&"bar".to_string(), "2.2.0").unwrap();
+        config.save(&top, Mode::Toml).await.unwrap();
+
+        let saved = std::fs::read_to_string(&top).unwrap();
+        assert!(saved.contains("# Pinned for the release"), "{}", saved);
+        assert!(
+            saved.contains("version = \"2.2.0\" # bumped by hand"),
+            "{}",
+            saved
+        );
+        // The url keeps resolving through `${version}`, so only the key and version are written
+        let saved = layer::parse(&saved, Mode::Toml).unwrap();
+        assert_eq!(
+            saved["packages"],
+            serde_json::json!([
+                {"name": "foo", "version": "1.1.0"},
+                {"name": "bar", "version": "2.2.0"},
+            ])
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("common.toml")).unwrap(),
+            COMMON
+        );
+
+        let config = Config::<SimplePackage>::from_file(&top, Mode::Toml)
+            .await
+            .unwrap();
+        let foo = config.get(&"foo".to_string()).unwrap();
+        assert_eq!(foo.version().unwrap(), "1.1.0");
+        assert_eq!(
+            foo.value().url(),
+            "https://example.com/releases/foo-1.1.0.tar.gz"
+        );
+        assert_eq!(config.packages().len(), 2);
+    }
+
+    #[test]
+    fn sets_the_version_only_where_it_is_a_whole_version() {
+        let mut package =
+            SimplePackage::new("foo", "1.0", "https://example.com/v1.0/foo-1.0.10.tar.gz");
+        package.set_version("1.1");
+        assert_eq!(
+            package.value().url(),
+            "https://example.com/v1.1/foo-1.0.10.tar.gz"
+        );
+
+        let mut package = SimplePackage::new("foo", "1.0", "https://example.com/11.0/foo-1.0");
+        package.set_version("2.0");
+        assert_eq!(package.value().url(), "https://example.com/11.0/foo-2.0");
+        assert_eq!(package.version().unwrap(), "2.0");
+    }
+
+    #[tokio::test]
+    async fn saves_new_packages_whole() {
+        let (_dir, top, mut config) = layered().await;
+        config.insert(SimplePackage::new(
+            "baz",
+            "0.1.0",
+            "https://example.com/baz.tar.gz",
+        ));
+        config.save(&top, Mode::Toml).await.unwrap();
+
+        let config = Config::<SimplePackage>::from_file(&top, Mode::Toml)
+            .await
+            .unwrap();
+        assert_eq!(config.packages().len(), 3);
+        let saved = layer::parse(&std::fs::read_to_string(&top).unwrap(), Mode::Toml).unwrap();
+        assert_eq!(saved["packages"].as_array().unwrap().len(), 2);
+    }
 }
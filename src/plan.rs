@@ -0,0 +1,65 @@
+use std::fmt::{Display, Formatter};
+
+/// An entry a fetch would fetch, with the version it has cached and the version it would fetch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub key: String,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+/// What a fetch would do, returned by `Fetcher::plan`
+///
+/// - `new`: Entries that aren't cached and would be fetched
+/// - `update`: Cached entries that changed and would be fetched again
+/// - `cached`: Entries whose cached value is up to date
+/// - `unresolved`: Cached git entries on a branch or tag, which can only be compared once the
+///   ref is resolved over the network
+/// - `orphaned`: Keys in the db that aren't entries anymore
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub new: Vec<Change>,
+    pub update: Vec<Change>,
+    pub cached: Vec<String>,
+    pub unresolved: Vec<String>,
+    pub orphaned: Vec<String>,
+}
+
+impl Plan {
+    /// Returns true if a fetch would fetch anything
+    pub fn has_changes(&self) -> bool {
+        !self.new.is_empty() || !self.update.is_empty()
+    }
+}
+
+fn version(version: &Option<String>) -> &str {
+    version.as_deref().unwrap_or("?")
+}
+
+/// One line per key, `+` new, `~` update, `=` cached, `?` unresolved and `-` orphaned
+impl Display for Plan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for change in &self.new {
+            writeln!(f, "+ {} {}", change.key, version(&change.to))?;
+        }
+        for change in &self.update {
+            writeln!(
+                f,
+                "~ {} {} -> {}",
+                change.key,
+                version(&change.from),
+                version(&change.to)
+            )?;
+        }
+        for key in &self.cached {
+            writeln!(f, "= {}", key)?;
+        }
+        for key in &self.unresolved {
+            writeln!(f, "? {}", key)?;
+        }
+        for key in &self.orphaned {
+            writeln!(f, "- {}", key)?;
+        }
+        Ok(())
+    }
+}
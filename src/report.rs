@@ -0,0 +1,112 @@
+use anyhow::{bail, Result};
+use std::fmt::{Display, Formatter};
+
+/// What a fetch did with an entry
+///
+/// - `Fetched`: The entry was fetched (and stored unless the fetch was rolled back)
+/// - `Cached`: The cached value was up to date
+/// - `Failed`: The entry couldn't be fetched, with the error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Fetched,
+    Cached,
+    Failed(String),
+}
+
+/// How the fetched values were stored
+///
+/// - `Immediate`: Each value was stored as soon as it was fetched
+/// - `Committed`: Every value was stored at once in a single transaction
+/// - `RolledBack`: Nothing was stored because an entry or the transaction failed
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Commit {
+    #[default]
+    Immediate,
+    Committed,
+    RolledBack,
+}
+
+/// What a fetch did, returned by `Fetcher::fetch_report`
+///
+/// - `outcomes`: The outcome of each entry, by key, with dependencies first
+/// - `commit`: How the fetched values were stored
+/// - `pruned`: The keys pruned after fetching, see `Fetcher::set_auto_prune`
+/// - `evicted`: The keys evicted after fetching, see `Fetcher::set_quota`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FetchReport {
+    pub outcomes: Vec<(String, Outcome)>,
+    pub commit: Commit,
+    pub pruned: Vec<String>,
+    pub evicted: Vec<String>,
+}
+
+impl FetchReport {
+    /// Returns the keys of the fetched entries
+    pub fn fetched(&self) -> impl Iterator<Item = &str> {
+        self.outcomes
+            .iter()
+            .filter(|(_, x)| *x == Outcome::Fetched)
+            .map(|(key, _)| key.as_str())
+    }
+
+    /// Returns the keys of the entries whose cached values were up to date
+    pub fn cached(&self) -> impl Iterator<Item = &str> {
+        self.outcomes
+            .iter()
+            .filter(|(_, x)| *x == Outcome::Cached)
+            .map(|(key, _)| key.as_str())
+    }
+
+    /// Returns the keys of the entries that failed with their errors
+    pub fn failed(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.outcomes.iter().filter_map(|(key, x)| match x {
+            Outcome::Failed(e) => Some((key.as_str(), e.as_str())),
+            _ => None,
+        })
+    }
+
+    /// Returns true if every entry was fetched or cached and nothing was rolled back
+    pub fn is_success(&self) -> bool {
+        self.commit != Commit::RolledBack && self.failed().next().is_none()
+    }
+
+    /// Fails with the errors of the failed entries
+    pub fn into_result(self) -> Result<Self> {
+        if self.is_success() {
+            return Ok(self);
+        }
+        let errors = self
+            .failed()
+            .map(|(_, e)| e.to_string())
+            .collect::<Vec<_>>();
+        match self.commit {
+            Commit::RolledBack if errors.is_empty() => bail!("The fetch was rolled back"),
+            Commit::RolledBack => bail!("{}, nothing was stored", errors.join(", ")),
+            _ => bail!("{}", errors.join(", ")),
+        }
+    }
+}
+
+/// One line per entry followed by how the values were stored
+impl Display for FetchReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (key, outcome) in &self.outcomes {
+            match outcome {
+                Outcome::Fetched => writeln!(f, "fetched {}", key)?,
+                Outcome::Cached => writeln!(f, "cached {}", key)?,
+                Outcome::Failed(e) => writeln!(f, "failed {}: {}", key, e)?,
+            }
+        }
+        for key in &self.pruned {
+            writeln!(f, "pruned {}", key)?;
+        }
+        for key in &self.evicted {
+            writeln!(f, "evicted {}", key)?;
+        }
+        match self.commit {
+            Commit::Immediate => Ok(()),
+            Commit::Committed => writeln!(f, "committed"),
+            Commit::RolledBack => writeln!(f, "rolled back"),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Formatter};
+
+/// A rule that rewrites urls before they are requested
+///
+/// The original url is still used for the cache key and `is_same`, so configs stay
+/// portable between online environments and ones that go through a proxy.
+///
+/// In a config file a rule is either a prefix or a regex (`$1` style captures are supported):
+///
+/// ```toml
+/// [[rewrites]]
+/// prefix = "https://github.com/"
+/// replacement = "https://proxy.internal/github/"
+///
+/// [[rewrites]]
+/// regex = "^https://([a-z]+)\\.example\\.com/"
+/// replacement = "https://proxy.internal/$1/"
+/// ```
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "RawRewriteRule", into = "RawRewriteRule")]
+pub struct RewriteRule {
+    pattern: Pattern,
+    replacement: String,
+}
+
+#[derive(Clone)]
+enum Pattern {
+    Prefix(String),
+    Regex(Regex),
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawRewriteRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    regex: Option<String>,
+    replacement: String,
+}
+
+impl TryFrom<RawRewriteRule> for RewriteRule {
+    type Error = String;
+
+    fn try_from(raw: RawRewriteRule) -> Result<Self, Self::Error> {
+        match (raw.prefix, raw.regex) {
+            (Some(prefix), None) => Ok(Self::prefix(prefix, raw.replacement)),
+            (None, Some(regex)) => Self::regex(&regex, raw.replacement).map_err(|e| e.to_string()),
+            _ => Err("a rewrite rule needs exactly one of `prefix` or `regex`".to_string()),
+        }
+    }
+}
+
+impl From<RewriteRule> for RawRewriteRule {
+    fn from(rule: RewriteRule) -> Self {
+        let (prefix, regex) = match rule.pattern {
+            Pattern::Prefix(prefix) => (Some(prefix), None),
+            Pattern::Regex(regex) => (None, Some(regex.as_str().to_string())),
+        };
+        Self {
+            prefix,
+            regex,
+            replacement: rule.replacement,
+        }
+    }
+}
+
+impl Debug for RewriteRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.pattern {
+            Pattern::Prefix(prefix) => write!(f, "{} -> {}", prefix, self.replacement),
+            Pattern::Regex(regex) => write!(f, "/{}/ -> {}", regex, self.replacement),
+        }
+    }
+}
+
+impl RewriteRule {
+    /// Replaces `prefix` with `replacement` in urls starting with it
+    pub fn prefix<S: Into<String>>(prefix: S, replacement: S) -> Self {
+        Self {
+            pattern: Pattern::Prefix(prefix.into()),
+            replacement: replacement.into(),
+        }
+    }
+
+    /// Replaces the first match of `regex` with `replacement`
+    pub fn regex<S: Into<String>>(regex: &str, replacement: S) -> anyhow::Result<Self> {
+        Ok(Self {
+            pattern: Pattern::Regex(Regex::new(regex)?),
+            replacement: replacement.into(),
+        })
+    }
+
+    /// Returns the rewritten url if the rule matches it
+    pub fn apply(&self, url: &str) -> Option<String> {
+        match &self.pattern {
+            Pattern::Prefix(prefix) => url
+                .strip_prefix(prefix.as_str())
+                .map(|rest| format!("{}{}", self.replacement, rest)),
+            Pattern::Regex(regex) => regex
+                .is_match(url)
+                .then(|| regex.replace(url, self.replacement.as_str()).into_owned()),
+        }
+    }
+}
+
+/// Rewrites a url with the first matching rule, or returns it unchanged
+///
+/// For `git+<repo url>` urls the rules are matched against the repository url
+pub fn rewrite<'a, I: IntoIterator<Item = &'a RewriteRule>>(rules: I, url: &str) -> String {
+    let (scheme, inner) = match url.strip_prefix("git+") {
+        Some(inner) => ("git+", inner),
+        None => ("", url),
+    };
+    rules
+        .into_iter()
+        .find_map(|rule| rule.apply(inner))
+        .map(|rewritten| format!("{}{}", scheme, rewritten))
+        .unwrap_or_else(|| url.to_string())
+}
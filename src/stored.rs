@@ -0,0 +1,322 @@
+use crate::history;
+use anyhow::{anyhow, bail, Result};
+use sled::transaction::{TransactionError, Transactional};
+use sled::{Batch, Db, IVec, Tree};
+use std::ops::Bound;
+
+/// Name of the sled tree holding the version of the format values are stored in
+pub(crate) const TREE: &str = "format";
+
+/// The key of the format version in its tree
+const VERSION: &str = "version";
+
+/// The format version, every stored value starts with a `Header` since version 1
+const FORMAT: u8 = 1;
+
+/// Number of values migrated in each transaction
+const CHUNK: usize = 1024;
+
+/// The byte every stored value starts with, saying how the rest of it is stored
+///
+/// - `compressed`: The rest is a zstd frame, encrypted if `encrypted` is set
+/// - `encrypted`: The rest is encrypted, see `crypt::encrypt`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub compressed: bool,
+    pub encrypted: bool,
+}
+
+impl Header {
+    const COMPRESSED: u8 = 0b01;
+    const ENCRYPTED: u8 = 0b10;
+
+    fn byte(&self) -> u8 {
+        let mut byte = 0;
+        if self.compressed {
+            byte |= Self::COMPRESSED;
+        }
+        if self.encrypted {
+            byte |= Self::ENCRYPTED;
+        }
+        byte
+    }
+
+    /// Returns the data an encrypted value of the key is bound to, its header and the key, so
+    /// neither can be changed without it failing to decrypt
+    pub(crate) fn aad(self, key: &[u8]) -> Vec<u8> {
+        self.prepend(key)
+    }
+
+    /// Returns the stored value of the rest, with the header in front
+    pub(crate) fn prepend(self, rest: &[u8]) -> Vec<u8> {
+        let mut stored = Vec::with_capacity(rest.len() + 1);
+        stored.push(self.byte());
+        stored.extend_from_slice(rest);
+        stored
+    }
+}
+
+/// Splits a stored value into its header and the rest of it
+pub(crate) fn split(stored: IVec) -> Result<(Header, IVec)> {
+    let Some(&byte) = stored.first() else {
+        bail!("Empty stored value");
+    };
+    if byte & !(Header::COMPRESSED | Header::ENCRYPTED) != 0 {
+        bail!("Unknown stored value header {:#04x}", byte);
+    }
+    let header = Header {
+        compressed: byte & Header::COMPRESSED != 0,
+        encrypted: byte & Header::ENCRYPTED != 0,
+    };
+    Ok((header, stored.subslice(1, stored.len() - 1)))
+}
+
+/// Puts a header in front of the values and previous versions of a db written before values
+/// had one
+///
+/// Values are migrated in chunks, each in a transaction that also records the last key it
+/// migrated, so an interrupted migration resumes after it instead of migrating a value twice.
+/// The format version is only set once everything is migrated.
+///
+/// Previous versions that can't be read are left as they are for `Fetcher::verify` to find
+pub(crate) fn migrate(db: &Db, history: &Tree) -> Result<()> {
+    let format = db.open_tree(TREE)?;
+    if format.contains_key(VERSION)? {
+        return Ok(());
+    }
+    migrate_tree(db, &format, "values", header_value)?;
+    migrate_tree(history, &format, "history", header_version)?;
+
+    let mut done = Batch::default();
+    done.remove("values");
+    done.remove("history");
+    done.insert(VERSION, &[FORMAT]);
+    format.apply_batch(done)?;
+    Ok(())
+}
+
+/// Migrates the values of the tree in chunks, resuming after the last key recorded as
+/// `progress` in the format tree
+fn migrate_tree(
+    tree: &Tree,
+    format: &Tree,
+    progress: &str,
+    upgrade: fn(&[u8]) -> Option<Vec<u8>>,
+) -> Result<()> {
+    loop {
+        let start = match format.get(progress)? {
+            Some(last) => Bound::Excluded(last),
+            None => Bound::Unbounded,
+        };
+        let mut chunk = Batch::default();
+        let mut last = None;
+        for item in tree.range((start, Bound::Unbounded)).take(CHUNK) {
+            let (key, value) = item?;
+            if let Some(value) = upgrade(&value) {
+                chunk.insert(&key, value);
+            }
+            last = Some(key);
+        }
+        let Some(last) = last else {
+            return Ok(());
+        };
+        (tree, format)
+            .transaction(|(tree, format)| {
+                tree.apply_batch(&chunk)?;
+                format.insert(progress, &last)?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError| anyhow!("Failed to migrate the db: {:?}", e))?;
+    }
+}
+
+/// Returns a value stored before values had a header with one
+fn header_value(value: &[u8]) -> Option<Vec<u8>> {
+    Some(Header::default().prepend(value))
+}
+
+/// Returns a previous version stored before values had a header with one, `None` if it
+/// can't be read
+fn header_version(bytes: &[u8]) -> Option<Vec<u8>> {
+    let (record, value) = history::decode(bytes).ok()?;
+    Some(history::encode(&record, &Header::default().prepend(value)))
+}
+
+/// Imports an export of a db (see `Fetcher::export`), giving the values and previous versions
+/// of an export from before values had a header one
+///
+/// The export's own format tree isn't imported, the db is of the current format afterwards,
+/// and an export of a newer format than this version reads is rejected before anything is
+/// imported
+pub(crate) fn import<I>(db: &Db, export: Vec<(Vec<u8>, Vec<u8>, I)>) -> Result<()>
+where
+    I: Iterator<Item = Vec<Vec<u8>>>,
+{
+    let mut version = None;
+    let mut collections = Vec::new();
+    for (kind, name, items) in export {
+        if name != TREE.as_bytes() {
+            collections.push((kind, name, items));
+            continue;
+        }
+        for pair in items {
+            if let [key, value] = pair.as_slice() {
+                if key.as_slice() == VERSION.as_bytes() {
+                    version = value.first().copied();
+                }
+            }
+        }
+    }
+    if let Some(version) = version.filter(|x| *x > FORMAT) {
+        bail!(
+            "The export is of format {}, newer than the format {} this version reads",
+            version,
+            FORMAT
+        );
+    }
+
+    let default = db.name();
+    let collections = collections
+        .into_iter()
+        .map(|(kind, name, items)| {
+            let upgrade: fn(Vec<Vec<u8>>) -> Vec<Vec<u8>> = match version {
+                Some(_) => |pair| pair,
+                None if name == default.as_ref() => |pair| upgrade_pair(pair, header_value),
+                None if name == history::TREE.as_bytes() => {
+                    |pair| upgrade_pair(pair, header_version)
+                }
+                None => |pair| pair,
+            };
+            (kind, name, items.map(upgrade))
+        })
+        .collect::<Vec<_>>();
+    db.import(collections);
+    db.open_tree(TREE)?.insert(VERSION, &[FORMAT])?;
+    Ok(())
+}
+
+/// Upgrades the value of an exported key-value pair, leaving it as it is if it can't be
+fn upgrade_pair(mut pair: Vec<Vec<u8>>, upgrade: fn(&[u8]) -> Option<Vec<u8>>) -> Vec<Vec<u8>> {
+    if let Some(value) = pair.last_mut() {
+        if let Some(upgraded) = upgrade(value) {
+            *value = upgraded;
+        }
+    }
+    pair
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::Record;
+
+    #[test]
+    fn splits_the_header() {
+        let header = Header {
+            compressed: true,
+            encrypted: false,
+        };
+        let (split_header, rest) = split(IVec::from(header.prepend(b"frame"))).unwrap();
+        assert_eq!(split_header, header);
+        assert_eq!(rest.as_ref(), b"frame");
+
+        let header = Header {
+            compressed: true,
+            encrypted: true,
+        };
+        assert_eq!(split(IVec::from(header.prepend(b""))).unwrap().0, header);
+
+        assert!(split(IVec::from(&[0x80, 1][..])).is_err());
+        assert!(split(IVec::from(&[][..])).is_err());
+    }
+
+    #[test]
+    fn migrates_a_db_once() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let history = db.open_tree(history::TREE).unwrap();
+        db.insert("key", &b"value"[..]).unwrap();
+        let record = Record::new(String::new(), String::new(), None, b"old");
+        let version = history::key(b"key", 1);
+        history
+            .insert(&version, history::encode(&record, b"old"))
+            .unwrap();
+
+        migrate(&db, &history).unwrap();
+        migrate(&db, &history).unwrap();
+
+        let (header, value) = split(db.get("key").unwrap().unwrap()).unwrap();
+        assert_eq!((header, value.as_ref()), (Header::default(), &b"value"[..]));
+        let bytes = history.get(&version).unwrap().unwrap();
+        let (_, stored) = history::decode(&bytes).unwrap();
+        let (header, value) = split(IVec::from(stored)).unwrap();
+        assert_eq!((header, value.as_ref()), (Header::default(), &b"old"[..]));
+    }
+
+    #[test]
+    fn resumes_an_interrupted_migration() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let history = db.open_tree(history::TREE).unwrap();
+        let format = db.open_tree(TREE).unwrap();
+        // `a` was migrated by a chunk before the migration was interrupted
+        db.insert("a", Header::default().prepend(b"a")).unwrap();
+        db.insert("b", &b"b"[..]).unwrap();
+        format.insert("values", "a").unwrap();
+
+        migrate(&db, &history).unwrap();
+
+        for key in ["a", "b"] {
+            let (header, value) = split(db.get(key).unwrap().unwrap()).unwrap();
+            assert_eq!(
+                (header, value.as_ref()),
+                (Header::default(), key.as_bytes())
+            );
+        }
+        assert_eq!(format.get(VERSION).unwrap().unwrap().as_ref(), &[FORMAT]);
+        assert_eq!(format.len(), 1);
+    }
+
+    #[test]
+    fn imports_exports_from_before_the_header() {
+        let old = sled::Config::new().temporary(true).open().unwrap();
+        old.insert("key", &b"value"[..]).unwrap();
+        let record = Record::new(String::new(), String::new(), None, b"old");
+        let version = history::key(b"key", 1);
+        let old_history = old.open_tree(history::TREE).unwrap();
+        old_history
+            .insert(&version, history::encode(&record, b"old"))
+            .unwrap();
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let history = db.open_tree(history::TREE).unwrap();
+        migrate(&db, &history).unwrap();
+        import(&db, old.export()).unwrap();
+
+        let (header, value) = split(db.get("key").unwrap().unwrap()).unwrap();
+        assert_eq!((header, value.as_ref()), (Header::default(), &b"value"[..]));
+        let bytes = history.get(&version).unwrap().unwrap();
+        let (_, stored) = history::decode(&bytes).unwrap();
+        assert_eq!(split(IVec::from(stored)).unwrap().1.as_ref(), b"old");
+
+        // A current export is imported as it is
+        let new = sled::Config::new().temporary(true).open().unwrap();
+        import(&new, db.export()).unwrap();
+        assert_eq!(new.get("key").unwrap(), db.get("key").unwrap());
+        let format = new.open_tree(TREE).unwrap();
+        assert_eq!(format.get(VERSION).unwrap().unwrap().as_ref(), &[FORMAT]);
+    }
+
+    #[test]
+    fn rejects_exports_of_a_newer_format() {
+        let newer = sled::Config::new().temporary(true).open().unwrap();
+        newer.insert("key", &b"value"[..]).unwrap();
+        newer
+            .open_tree(TREE)
+            .unwrap()
+            .insert(VERSION, &[FORMAT + 1])
+            .unwrap();
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        assert!(import(&db, newer.export()).is_err());
+        assert!(db.is_empty());
+    }
+}
@@ -48,16 +48,41 @@ impl GHValue {
     }
 }
 
+/// Value of a git snapshot, the response is a tarball of the tree at `commit`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GitValue {
+    url: String,
+    reference: String,
+    commit: Option<String>,
+    response: Vec<u8>,
+}
+
+impl GitValue {
+    pub fn new(url: String, reference: String) -> Self {
+        Self {
+            url,
+            reference,
+            commit: None,
+            response: Vec::new(),
+        }
+    }
+
+    /// Returns the commit the reference resolved to when it was fetched
+    pub fn commit(&self) -> Option<&str> {
+        self.commit.as_deref()
+    }
+}
+
 impl EntryValue for SimpleValue {
     fn bytes(&self) -> Vec<u8> {
         bincode::serialize(&self).unwrap()
     }
 
-    fn from_ivec(value: IVec) -> Self
+    fn try_from_ivec(value: IVec) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        bincode::deserialize(value.as_ref()).unwrap()
+        Ok(bincode::deserialize(value.as_ref())?)
     }
 
     fn url(&self) -> String {
@@ -82,11 +107,11 @@ impl EntryValue for GHValue {
         bincode::serialize(&self).unwrap()
     }
 
-    fn from_ivec(value: IVec) -> Self
+    fn try_from_ivec(value: IVec) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        bincode::deserialize(value.as_ref()).unwrap()
+        Ok(bincode::deserialize(value.as_ref())?)
     }
 
     fn url(&self) -> String {
@@ -111,3 +136,72 @@ impl EntryValue for GHValue {
             && self.asset == other.asset
     }
 }
+
+impl EntryValue for GitValue {
+    fn bytes(&self) -> Vec<u8> {
+        bincode::serialize(&self).unwrap()
+    }
+
+    fn try_from_ivec(value: IVec) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(bincode::deserialize(value.as_ref())?)
+    }
+
+    fn url(&self) -> String {
+        format!("git+{}#{}", self.url, self.reference)
+    }
+
+    fn response(&self) -> Cow<'_, [u8]> {
+        Cow::from(&self.response)
+    }
+
+    fn set_response(&mut self, response: &[u8]) {
+        self.response = response.to_vec();
+    }
+
+    fn is_same(&self, other: &Self) -> bool
+    where
+        Self: Sized,
+    {
+        self.url == other.url && self.commit.is_some() && self.commit == other.commit
+    }
+
+    fn revision(&self) -> Option<String> {
+        self.commit.clone()
+    }
+
+    fn set_revision(&mut self, revision: &str) {
+        self.commit = Some(revision.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes a field the way bincode does, with its length as a little endian u64
+    fn field(bytes: &[u8]) -> Vec<u8> {
+        let mut field = (bytes.len() as u64).to_le_bytes().to_vec();
+        field.extend_from_slice(bytes);
+        field
+    }
+
+    #[test]
+    fn decodes_baseline_simple_value() {
+        // `version`, `url` and `response`, as cached by the first release
+        let bytes = [
+            field(b"0.6.0"),
+            field(b"https://example.com/a.tar.gz"),
+            field(b"payload"),
+        ]
+        .concat();
+
+        let value = SimpleValue::try_from_ivec(IVec::from(bytes.clone())).unwrap();
+        assert_eq!(value.url(), "https://example.com/a.tar.gz");
+        assert_eq!(value.response().as_ref(), b"payload");
+        assert!(value.is_same(&SimpleValue::new("0.6.0".into(), String::new())));
+        assert_eq!(value.bytes(), bytes);
+    }
+}
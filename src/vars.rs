@@ -0,0 +1,194 @@
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// Returns whether any string in the value contains a `${...}` reference
+pub fn has_references(value: &Value) -> bool {
+    match value {
+        Value::String(s) => s.contains("${"),
+        Value::Array(values) => values.iter().any(has_references),
+        Value::Object(map) => map.values().any(has_references),
+        _ => false,
+    }
+}
+
+/// Replaces every `${...}` reference in the string with the value returned by `lookup`
+///
+/// `$${` is an escaped, literal `${`
+pub fn interpolate<F>(s: &str, mut lookup: F) -> Result<String>
+where
+    F: FnMut(&str) -> Result<String>,
+{
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = escaped;
+        } else if let Some(reference) = rest.strip_prefix("${") {
+            let end = reference
+                .find('}')
+                .ok_or_else(|| anyhow!("Unterminated `${{` in {:?}", s))?;
+            out.push_str(&lookup(reference[..end].trim())?);
+            rest = &reference[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Resolves the `[vars]` table, vars may reference the environment and each other
+pub fn resolve_vars(vars: &BTreeMap<String, String>) -> Result<BTreeMap<String, String>> {
+    let mut resolved = BTreeMap::new();
+    for name in vars.keys() {
+        resolve_var(name, vars, &mut resolved, &mut Vec::new())?;
+    }
+    Ok(resolved)
+}
+
+fn resolve_var(
+    name: &str,
+    vars: &BTreeMap<String, String>,
+    resolved: &mut BTreeMap<String, String>,
+    stack: &mut Vec<String>,
+) -> Result<String> {
+    if let Some(value) = resolved.get(name) {
+        return Ok(value.clone());
+    }
+    if stack.iter().any(|x| x == name) {
+        bail!("Variable `{}` references itself", name);
+    }
+    let template = vars
+        .get(name)
+        .ok_or_else(|| anyhow!("Undefined variable `{}`", name))?;
+    stack.push(name.to_string());
+    let value = interpolate(template, |reference| match env(reference) {
+        Some(value) => value,
+        None => resolve_var(reference, vars, resolved, stack),
+    })?;
+    stack.pop();
+    resolved.insert(name.to_string(), value.clone());
+    Ok(value)
+}
+
+/// Looks up `env:NAME` references, returning `None` for other references
+fn env(reference: &str) -> Option<Result<String>> {
+    let name = reference.strip_prefix("env:")?;
+    Some(std::env::var(name).map_err(|_| anyhow!("Environment variable `{}` is not set", name)))
+}
+
+/// Resolves the references in every string of a package
+///
+/// `${version}` and `${name}` are the package's own `version` and `name` fields (which may
+/// themselves reference vars and the environment), other references are vars or `env:NAME`.
+/// Errors name the package and the field.
+pub fn resolve_package(
+    package: &mut Map<String, Value>,
+    vars: &BTreeMap<String, String>,
+) -> Result<()> {
+    let lookup = |reference: &str| match env(reference) {
+        Some(value) => value,
+        None => vars
+            .get(reference)
+            .cloned()
+            .ok_or_else(|| anyhow!("Undefined variable `{}`", reference)),
+    };
+
+    let mut own = BTreeMap::new();
+    for field in ["name", "version"] {
+        if let Some(Value::String(value)) = package.get_mut(field) {
+            *value = interpolate(value, &lookup)
+                .map_err(|e| anyhow!("{} in the {} of a package", e, field))?;
+            own.insert(field, value.clone());
+        }
+    }
+    let package_name = own.get("name").cloned().unwrap_or_default();
+    for (field, value) in package.iter_mut() {
+        if own.contains_key(field.as_str()) {
+            continue;
+        }
+        resolve_value(value, &|reference: &str| match own.get(reference) {
+            Some(value) => Ok(value.clone()),
+            None if reference == "name" || reference == "version" => {
+                bail!("Package has no `{}` field", reference)
+            }
+            None => lookup(reference),
+        })
+        .map_err(|e| anyhow!("{} in the {} of package {}", e, field, package_name))?;
+    }
+    Ok(())
+}
+
+/// Resolves the references in every string of a value
+pub fn resolve_value<F>(value: &mut Value, lookup: &F) -> Result<()>
+where
+    F: Fn(&str) -> Result<String>,
+{
+    match value {
+        Value::String(s) if s.contains("${") => *s = interpolate(s, lookup)?,
+        Value::Array(values) => {
+            for value in values {
+                resolve_value(value, lookup)?;
+            }
+        }
+        Value::Object(map) => {
+            for value in map.values_mut() {
+                resolve_value(value, lookup)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn interpolates_references() {
+        let lookup = |reference: &str| Ok(format!("<{}>", reference));
+        assert_eq!(interpolate("a${ b }c$d", lookup).unwrap(), "a<b>c$d");
+        assert_eq!(interpolate("$${b} ${b}", lookup).unwrap(), "${b} <b>");
+        let error = interpolate("a${b", lookup).unwrap_err();
+        assert_eq!(error.to_string(), "Unterminated `${` in \"a${b\"");
+    }
+
+    #[test]
+    fn resolves_vars_referencing_each_other() {
+        let resolved = resolve_vars(&vars(&[("a", "${b}/a"), ("b", "$${b}")])).unwrap();
+        assert_eq!(resolved["a"], "${b}/a");
+
+        let error = resolve_vars(&vars(&[("a", "${b}"), ("b", "${a}")])).unwrap_err();
+        assert_eq!(error.to_string(), "Variable `a` references itself");
+        let error = resolve_vars(&vars(&[("a", "${a}")])).unwrap_err();
+        assert_eq!(error.to_string(), "Variable `a` references itself");
+        let error = resolve_vars(&vars(&[("a", "${c}")])).unwrap_err();
+        assert_eq!(error.to_string(), "Undefined variable `c`");
+    }
+
+    #[test]
+    fn resolves_environment_variables() {
+        std::env::set_var("QUICKFETCH_VARS_TEST", "from env");
+        let resolved = resolve_vars(&vars(&[("a", "${env:QUICKFETCH_VARS_TEST}!")])).unwrap();
+        assert_eq!(resolved["a"], "from env!");
+
+        let error = resolve_vars(&vars(&[("a", "${env:QUICKFETCH_VARS_UNSET}")])).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Environment variable `QUICKFETCH_VARS_UNSET` is not set"
+        );
+    }
+}
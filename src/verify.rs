@@ -0,0 +1,121 @@
+use crate::report::FetchReport;
+use std::fmt::{Display, Formatter};
+
+/// How `Fetcher::verify` repairs the problems it finds
+///
+/// - `None`: Only report them
+/// - `Delete`: Delete the bad records and versions, and replace each bad value with its newest
+///   intact previous version, deleting it if there's none
+/// - `Refetch`: Repair them like `Delete` and fetch the entries whose values were deleted again
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Repair {
+    #[default]
+    None,
+    Delete,
+    Refetch,
+}
+
+/// A problem found by `Fetcher::verify`
+///
+/// - `Corrupt`: The cached value can't be read or doesn't match the size or checksum of its record
+/// - `BadRecord`: The record of a cached value can't be read
+/// - `Missing`: A record whose cached value is missing
+/// - `BadVersion`: A previous version that can't be read or doesn't match its record
+/// - `Orphaned`: A previous version of a key that isn't cached, or whose history key can't be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    Corrupt { key: String, error: String },
+    BadRecord { key: String },
+    Missing { key: String },
+    BadVersion { key: String, id: u64, error: String },
+    Orphaned { key: String, id: u64 },
+}
+
+impl Issue {
+    /// Returns the key the problem was found in
+    pub fn key(&self) -> &str {
+        match self {
+            Issue::Corrupt { key, .. }
+            | Issue::BadRecord { key }
+            | Issue::Missing { key }
+            | Issue::BadVersion { key, .. }
+            | Issue::Orphaned { key, .. } => key,
+        }
+    }
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Issue::Corrupt { key, error } => write!(f, "corrupt {}: {}", key, error),
+            Issue::BadRecord { key } => write!(f, "unreadable record {}", key),
+            Issue::Missing { key } => write!(f, "missing {}", key),
+            Issue::BadVersion { key, id, error } => {
+                write!(f, "corrupt {} version {}: {}", key, id, error)
+            }
+            Issue::Orphaned { key, id } => write!(f, "orphaned {} version {}", key, id),
+        }
+    }
+}
+
+/// What `Fetcher::verify` found and repaired
+///
+/// - `values`: The number of cached values checked
+/// - `versions`: The number of previous versions checked
+/// - `issues`: The problems found
+/// - `restored`: The keys whose cached values were replaced by a previous version, with its id
+/// - `deleted`: The keys whose cached values were deleted by the repair
+/// - `refetch`: What refetching the deleted entries did, with `Repair::Refetch`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub values: usize,
+    pub versions: usize,
+    pub issues: Vec<Issue>,
+    pub restored: Vec<(String, u64)>,
+    pub deleted: Vec<String>,
+    pub refetch: Option<FetchReport>,
+}
+
+impl VerifyReport {
+    /// Returns true if no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// One line per problem, followed by the repairs and a summary
+impl Display for VerifyReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for issue in &self.issues {
+            writeln!(f, "{}", issue)?;
+        }
+        for (key, id) in &self.restored {
+            writeln!(f, "restored {} version {}", key, id)?;
+        }
+        for key in &self.deleted {
+            writeln!(f, "deleted {}", key)?;
+        }
+        if let Some(refetch) = &self.refetch {
+            write!(f, "{}", refetch)?;
+        }
+        writeln!(
+            f,
+            "checked {} values and {} versions, {} problems",
+            self.values,
+            self.versions,
+            self.issues.len()
+        )
+    }
+}
+
+/// A repair `Fetcher::verify` makes for a problem
+///
+/// - `Restore`: Replace the cached value of a key and its record with its newest intact previous
+///   version, or delete them if there's none
+/// - `Record`: Delete the record of a key, and fetch it again if its value is missing
+/// - `Version`: Delete a previous version by its history key
+pub(crate) enum Fix {
+    Restore(sled::IVec),
+    Record(sled::IVec, bool),
+    Version(sled::IVec),
+}